@@ -0,0 +1,69 @@
+use std::{env, process};
+
+use bytes::Bytes;
+use orion::{
+    app,
+    envelope::Envelope,
+    nats_client::NatsClient,
+    server::{HandlerError, Server},
+    session::{SessionServiceClient, SessionUpdate},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+struct EnterRequest {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+struct EnterResponse {
+    #[prost(string, tag = "1")]
+    uid: String,
+    #[prost(uint32, tag = "2")]
+    server_id: u32,
+}
+
+/// records the player's name in its session at the gate and welcomes it
+async fn enter(nats: NatsClient, envelope: Envelope) -> Result<Bytes, HandlerError> {
+    let req: EnterRequest = envelope.decode_payload()?;
+    let update = SessionUpdate::default()
+        .set("name", &req.name)
+        .map_err(|e| HandlerError::Failed(e.to_string()))?;
+    let sessions = SessionServiceClient::new(nats).at(envelope.gate_id);
+    match sessions.update(envelope.uid.clone(), update).await {
+        Ok(true) => {}
+        Ok(false) => warn!("Player {} left before entering", envelope.uid),
+        Err(e) => return Err(HandlerError::Failed(e.to_string())),
+    }
+    let resp = EnterResponse {
+        uid: envelope.uid.clone(),
+        server_id: app().uuid(),
+    };
+    Ok(envelope.encode_reply(&resp)?)
+}
+
+#[orion::init_tracing]
+#[tokio::main]
+async fn main() {
+    let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_string());
+    let nats = match orion::nats_client::connect(nats_url).await {
+        Ok(nats) => nats,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let handler_nats = nats.clone();
+    let result = Server::new(nats)
+        .handler("connector.entry.enter", move |envelope| {
+            enter(handler_nats.clone(), envelope)
+        })
+        .run()
+        .await;
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,88 @@
+use std::{sync::Arc, thread};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use gate::client::{ClientManager, NetClient};
+use orion::CloseReason;
+
+const CLIENTS: u32 = 50_000;
+const OPS_PER_THREAD: u32 = 20_000;
+
+struct BenchClient;
+
+impl NetClient for BenchClient {
+    async fn onopen(self: Arc<Self>) {}
+    async fn receive_msg(self: Arc<Self>, _msg: Bytes) {}
+    async fn onclose(self: Arc<Self>, _reason: CloseReason) {}
+    async fn close(self: Arc<Self>) {}
+}
+
+fn populated() -> ClientManager<BenchClient> {
+    let client_mgr = ClientManager::new();
+    for id in 0..CLIENTS {
+        client_mgr.add_client(id, BenchClient);
+        client_mgr.bind_connection(format!("user{}", id), id);
+    }
+    client_mgr
+}
+
+/// every thread looks clients up by socket id and by uid, like packets arriving from
+/// many connections at once
+fn lookups(c: &mut Criterion) {
+    let client_mgr = populated();
+    let mut group = c.benchmark_group("lookups");
+    for threads in [1, 4, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for t in 0..n {
+                        let client_mgr = &client_mgr;
+                        s.spawn(move || {
+                            for i in 0..OPS_PER_THREAD {
+                                let id = (i * 7919 + t * 104729) % CLIENTS;
+                                assert!(client_mgr.get_client(id).is_some());
+                                let uid = format!("user{}", id);
+                                assert!(client_mgr.get_client_by_uid(&uid).is_some());
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// lookups mixed with connections opening, binding and closing
+fn churn(c: &mut Criterion) {
+    let client_mgr = populated();
+    let mut group = c.benchmark_group("churn");
+    for threads in [1, 4, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for t in 0..n {
+                        let client_mgr = &client_mgr;
+                        s.spawn(move || {
+                            for i in 0..OPS_PER_THREAD {
+                                let id = (i * 7919 + t * 104729) % CLIENTS;
+                                if i % 10 == 0 {
+                                    let new_id = CLIENTS + t * OPS_PER_THREAD + i;
+                                    client_mgr.add_client(new_id, BenchClient);
+                                    client_mgr.bind_connection(format!("guest{}", new_id), new_id);
+                                    client_mgr.remove_client(new_id);
+                                } else {
+                                    client_mgr.get_client_by_uid(&format!("user{}", id));
+                                }
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookups, churn);
+criterion_main!(benches);
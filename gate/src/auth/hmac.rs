@@ -0,0 +1,110 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{AuthError, AuthRequest, Authenticator};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Verifies tokens of the form `{uid}.{expires}.{signature}`, where `expires` is a unix
+/// timestamp in seconds and `signature` is the base64url HMAC-SHA256 of `{uid}.{expires}`.
+pub struct HmacAuthenticator {
+    secret: Vec<u8>,
+}
+
+impl HmacAuthenticator {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        HmacAuthenticator {
+            secret: secret.into(),
+        }
+    }
+
+    /// issues a token, used by the login service sharing the secret
+    pub fn sign(&self, uid: &str, expires: u64) -> String {
+        let payload = format!("{}.{}", uid, expires);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify(&self, token: &str, now: u64) -> Result<String, AuthError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+        let (uid, expires) = payload.rsplit_once('.').ok_or(AuthError::Malformed)?;
+        let expires: u64 = expires.parse().map_err(|_| AuthError::Malformed)?;
+        if uid.is_empty() {
+            return Err(AuthError::Malformed);
+        }
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::Malformed)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidSignature)?;
+        if expires <= now {
+            return Err(AuthError::Expired);
+        }
+        Ok(uid.to_string())
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[async_trait]
+impl Authenticator for HmacAuthenticator {
+    async fn authenticate(&self, req: AuthRequest<'_>) -> Result<String, AuthError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.verify(req.token, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::test_request, protocol::handshake::HandshakeRequest};
+
+    #[test]
+    fn test_sign_verify() {
+        let auth = HmacAuthenticator::new("secret");
+        let token = auth.sign("user.1", 1000);
+        assert_eq!(auth.verify(&token, 999), Ok("user.1".to_string()));
+        assert_eq!(auth.verify(&token, 1000), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let token = HmacAuthenticator::new("secret").sign("user1", 1000);
+        let auth = HmacAuthenticator::new("other");
+        assert_eq!(auth.verify(&token, 0), Err(AuthError::InvalidSignature));
+
+        let auth = HmacAuthenticator::new("secret");
+        let forged = token.replacen("user1", "user2", 1);
+        assert_eq!(auth.verify(&forged, 0), Err(AuthError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let auth = HmacAuthenticator::new("secret");
+        assert_eq!(auth.verify("user1", 0), Err(AuthError::Malformed));
+        assert_eq!(auth.verify("user1.abc.sig", 0), Err(AuthError::Malformed));
+        assert_eq!(auth.verify("user1.1000.!!", 0), Err(AuthError::Malformed));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let auth = HmacAuthenticator::new("secret");
+        let token = auth.sign("user1", u64::MAX);
+        let handshake = HandshakeRequest::default();
+        let uid = auth.authenticate(test_request(&token, &handshake)).await;
+        assert_eq!(uid, Ok("user1".to_string()));
+    }
+}
@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use super::{AuthError, AuthRequest, Authenticator};
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Verifies JWTs and uses the `sub` claim as the uid. `exp` is required.
+pub struct JwtAuthenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuthenticator {
+    pub fn new(key: DecodingKey, validation: Validation) -> Self {
+        JwtAuthenticator { key, validation }
+    }
+
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self::new(
+            DecodingKey::from_secret(secret),
+            Validation::new(Algorithm::HS256),
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> Result<String, AuthError> {
+        let data = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation).map_err(
+            |e| match e.kind() {
+                ErrorKind::ExpiredSignature => AuthError::Expired,
+                ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+                ErrorKind::InvalidToken
+                | ErrorKind::Base64(_)
+                | ErrorKind::Json(_)
+                | ErrorKind::Utf8(_)
+                | ErrorKind::MissingRequiredClaim(_) => AuthError::Malformed,
+                _ => AuthError::Rejected(e.to_string()),
+            },
+        )?;
+        if data.claims.sub.is_empty() {
+            return Err(AuthError::Malformed);
+        }
+        Ok(data.claims.sub)
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, req: AuthRequest<'_>) -> Result<String, AuthError> {
+        self.verify(req.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+    use crate::{auth::test_request, protocol::handshake::HandshakeRequest};
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        exp: u64,
+    }
+
+    fn token(secret: &[u8], sub: &str, exp: u64) -> String {
+        encode(
+            &Header::default(),
+            &TestClaims { sub, exp },
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let auth = JwtAuthenticator::hs256(b"secret");
+        let token = token(b"secret", "user1", u32::MAX as u64);
+        let handshake = HandshakeRequest::default();
+        let uid = auth.authenticate(test_request(&token, &handshake)).await;
+        assert_eq!(uid, Ok("user1".to_string()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let auth = JwtAuthenticator::hs256(b"secret");
+        let forged = token(b"other", "user1", u32::MAX as u64);
+        assert_eq!(auth.verify(&forged), Err(AuthError::InvalidSignature));
+        let expired = token(b"secret", "user1", 1);
+        assert_eq!(auth.verify(&expired), Err(AuthError::Expired));
+        assert_eq!(auth.verify("not a jwt"), Err(AuthError::Malformed));
+    }
+}
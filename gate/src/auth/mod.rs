@@ -0,0 +1,82 @@
+pub mod hmac;
+pub mod jwt;
+
+use std::{fmt, net::SocketAddr};
+
+use async_trait::async_trait;
+
+use crate::protocol::handshake::HandshakeRequest;
+
+pub use self::hmac::HmacAuthenticator;
+pub use self::jwt::JwtAuthenticator;
+
+/// what the client presented in its handshake
+pub struct AuthRequest<'a> {
+    pub token: &'a str,
+    pub addr: SocketAddr,
+    pub handshake: &'a HandshakeRequest,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    Rejected(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Malformed => write!(f, "malformed token"),
+            AuthError::InvalidSignature => write!(f, "invalid signature"),
+            AuthError::Expired => write!(f, "token expired"),
+            AuthError::Rejected(reason) => write!(f, "rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Called by the gate during the handshake, returns the authenticated uid.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, req: AuthRequest<'_>) -> Result<String, AuthError>;
+}
+
+/// Trusts the token as the uid, only meant for development.
+pub struct TrustAuthenticator;
+
+#[async_trait]
+impl Authenticator for TrustAuthenticator {
+    async fn authenticate(&self, req: AuthRequest<'_>) -> Result<String, AuthError> {
+        if req.token.is_empty() {
+            return Err(AuthError::Malformed);
+        }
+        Ok(req.token.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_request<'a>(token: &'a str, handshake: &'a HandshakeRequest) -> AuthRequest<'a> {
+    AuthRequest {
+        token,
+        addr: "127.0.0.1:9001".parse().unwrap(),
+        handshake,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trust() {
+        let handshake = HandshakeRequest::default();
+        let auth = TrustAuthenticator;
+        let uid = auth.authenticate(test_request("user1", &handshake)).await;
+        assert_eq!(uid, Ok("user1".to_string()));
+        let uid = auth.authenticate(test_request("", &handshake)).await;
+        assert_eq!(uid, Err(AuthError::Malformed));
+    }
+}
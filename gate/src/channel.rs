@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use orion::{broadcast::PushPayload, channel::ChannelCommand};
+use tracing::warn;
+
+use crate::{
+    client::{socket_client::Client, ClientManager},
+    global,
+    protocol::push::{PushError, SharedPush},
+};
+
+/// Named groups of uids the gate can push one message to.
+#[derive(Clone, Default)]
+pub struct ChannelManager {
+    channels: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl ChannelManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns false if the channel already exists
+    pub fn create(&self, channel: &str) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.contains_key(channel) {
+            return false;
+        }
+        channels.insert(channel.to_string(), HashSet::new());
+        true
+    }
+
+    pub fn destroy(&self, channel: &str) -> bool {
+        self.channels.lock().unwrap().remove(channel).is_some()
+    }
+
+    /// creates the channel if it does not exist, returns false if already a member
+    pub fn join(&self, channel: &str, uid: &str) -> bool {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .insert(uid.to_string())
+    }
+
+    /// the channel is dropped once its last member leaves
+    pub fn leave(&self, channel: &str, uid: &str) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(members) = channels.get_mut(channel) else {
+            return false;
+        };
+        let left = members.remove(uid);
+        if members.is_empty() {
+            channels.remove(channel);
+        }
+        left
+    }
+
+    /// removes the uid from every channel when it disconnects, returns how many it left
+    pub fn leave_all(&self, uid: &str) -> usize {
+        let mut left = 0;
+        self.channels.lock().unwrap().retain(|_, members| {
+            if !members.remove(uid) {
+                return true;
+            }
+            left += 1;
+            !members.is_empty()
+        });
+        left
+    }
+
+    pub fn members(&self, channel: &str) -> Vec<String> {
+        match self.channels.lock().unwrap().get(channel) {
+            Some(members) => members.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    /// pushes to the members connected to this gate, the packet is encoded once per codec
+    /// and compression setting and shared, returns how many clients it was sent to
+    pub fn broadcast(
+        &self,
+        client_mgr: &ClientManager<Client>,
+        channel: &str,
+        protocol_id: u16,
+        payload: PushPayload,
+    ) -> usize {
+        let clients = self
+            .members(channel)
+            .into_iter()
+            .filter_map(|uid| client_mgr.get_client_by_uid(&uid));
+        push_to(clients, SharedPush::new(protocol_id, payload), channel)
+    }
+
+    pub fn execute(&self, cmd: ChannelCommand, payload: PushPayload) {
+        match cmd {
+            ChannelCommand::Create { channel } => {
+                self.create(&channel);
+            }
+            ChannelCommand::Destroy { channel } => {
+                self.destroy(&channel);
+            }
+            ChannelCommand::Join { channel, uid } => {
+                self.join(&channel, &uid);
+            }
+            ChannelCommand::Leave { channel, uid } => {
+                self.leave(&channel, &uid);
+            }
+            ChannelCommand::LeaveAll { uid } => {
+                self.leave_all(&uid);
+            }
+            ChannelCommand::Broadcast { channel, route } => {
+                let Some(protocol_id) = global::routes().id(&route) else {
+                    warn!(
+                        "Failed to broadcast to {}: unknown route {}",
+                        channel, route
+                    );
+                    return;
+                };
+                self.broadcast(
+                    &global::client_manager_copy(),
+                    &channel,
+                    protocol_id,
+                    payload,
+                );
+            }
+        }
+    }
+}
+
+/// never waits for a client, returns how many it was sent to
+pub fn push_to(
+    clients: impl IntoIterator<Item = Arc<Client>>,
+    mut push: SharedPush,
+    target: &str,
+) -> usize {
+    let (mut sent, mut no_payload, mut full) = (0, 0, 0);
+    for client in clients {
+        match client.send_push(&mut push) {
+            Ok(()) => sent += 1,
+            Err(PushError::NoPayload(_)) => no_payload += 1,
+            Err(PushError::Full) => full += 1,
+        }
+    }
+    if no_payload > 0 {
+        warn!(
+            "Skipped {} clients of {} whose codec the push has no payload for",
+            no_payload, target
+        );
+    }
+    if full > 0 {
+        warn!(
+            "Dropped the push to {} for {} clients too slow to read",
+            target, full
+        );
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_destroy() {
+        let channels = ChannelManager::new();
+        assert!(channels.create("world"));
+        assert!(!channels.create("world"));
+        assert!(channels.destroy("world"));
+        assert!(!channels.destroy("world"));
+    }
+
+    #[test]
+    fn test_join_leave() {
+        let channels = ChannelManager::new();
+        assert!(channels.join("guild.1", "user1"));
+        assert!(!channels.join("guild.1", "user1"));
+        assert!(channels.join("guild.1", "user2"));
+        let mut members = channels.members("guild.1");
+        members.sort();
+        assert_eq!(members, vec!["user1", "user2"]);
+
+        assert!(channels.leave("guild.1", "user1"));
+        assert!(!channels.leave("guild.1", "user1"));
+        assert!(!channels.leave("guild.2", "user1"));
+        assert_eq!(channels.members("guild.1"), vec!["user2"]);
+        assert!(channels.members("guild.2").is_empty());
+
+        assert!(channels.leave("guild.1", "user2"));
+        assert!(channels.create("guild.1"));
+    }
+
+    #[test]
+    fn test_leave_all() {
+        let channels = ChannelManager::new();
+        channels.join("world", "user1");
+        channels.join("world", "user2");
+        channels.join("guild.1", "user1");
+        channels.create("guild.2");
+
+        assert_eq!(channels.leave_all("user1"), 2);
+        assert_eq!(channels.leave_all("user1"), 0);
+        assert_eq!(channels.members("world"), vec!["user2"]);
+        // emptied by the uid leaving, unlike guild.2 which never had members
+        assert!(channels.create("guild.1"));
+        assert!(!channels.create("guild.2"));
+    }
+
+    #[test]
+    fn test_broadcast_without_clients() {
+        let channels = ChannelManager::new();
+        channels.join("world", "user1");
+        let sent = channels.broadcast(&ClientManager::new(), "world", 1, PushPayload::new());
+        assert_eq!(sent, 0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use orion::{route::RouteDict, RateLimit, TokenBucket};
+
+use crate::protocol::message;
+
+/// Request limits shared by every client: a rate per protocol id, a cap on the requests a
+/// client may have waiting for a backend and how long they may wait.
+///
+/// The file format is one `route per_second burst` per line, `#` starts a comment.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    routes: HashMap<u16, RateLimit>,
+    max_in_flight: usize,
+    timeout: Duration,
+}
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum LimitsError {
+    Io(std::io::Error),
+    UnknownRoute(String),
+    Invalid(String),
+}
+
+impl fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitsError::Io(e) => write!(f, "failed to read request limits: {}", e),
+            LimitsError::UnknownRoute(route) => write!(f, "unknown route: {}", route),
+            LimitsError::Invalid(line) => write!(f, "invalid limit: {}", line),
+        }
+    }
+}
+
+impl std::error::Error for LimitsError {}
+
+impl RequestLimits {
+    pub fn new(max_in_flight: usize) -> Self {
+        RequestLimits {
+            routes: HashMap::new(),
+            max_in_flight,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// requests not answered by then get an error response
+    pub fn request_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn route(mut self, proto_id: u16, limit: RateLimit) -> Self {
+        self.routes.insert(proto_id, limit);
+        self
+    }
+
+    pub fn parse(
+        text: &str,
+        routes: &RouteDict,
+        max_in_flight: usize,
+    ) -> Result<Self, LimitsError> {
+        let mut limits = RequestLimits::new(max_in_flight);
+        let lines = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty());
+        for line in lines {
+            let invalid = || LimitsError::Invalid(line.to_string());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [route, per_second, burst] = fields[..] else {
+                return Err(invalid());
+            };
+            let proto_id = routes
+                .id(route)
+                .ok_or_else(|| LimitsError::UnknownRoute(route.to_string()))?;
+            let limit = RateLimit {
+                per_second: per_second.parse().map_err(|_| invalid())?,
+                burst: burst.parse().map_err(|_| invalid())?,
+            };
+            limits = limits.route(proto_id, limit);
+        }
+        Ok(limits)
+    }
+
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        routes: &RouteDict,
+        max_in_flight: usize,
+    ) -> Result<Self, LimitsError> {
+        let text = fs::read_to_string(path).map_err(LimitsError::Io)?;
+        Self::parse(&text, routes, max_in_flight)
+    }
+}
+
+/// per client state of [`RequestLimits`], including the ids of the requests in flight
+#[derive(Debug)]
+pub struct RequestLimiter {
+    buckets: HashMap<u16, Mutex<TokenBucket>>,
+    in_flight: Arc<Mutex<HashSet<u32>>>,
+    max_in_flight: usize,
+}
+
+/// the request stays in flight, and its id taken, until dropped
+#[derive(Debug)]
+pub struct InFlight {
+    msg_id: u32,
+    in_flight: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.msg_id);
+    }
+}
+
+impl RequestLimiter {
+    pub fn new(limits: &RequestLimits) -> Self {
+        RequestLimiter {
+            buckets: limits
+                .routes
+                .iter()
+                .map(|(id, l)| (*id, Mutex::new(TokenBucket::new(l.per_second, l.burst))))
+                .collect(),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            max_in_flight: limits.max_in_flight,
+        }
+    }
+
+    /// the error is the code of the error response sent back to the client
+    pub fn acquire(&self, proto_id: u16, msg_id: u32) -> Result<InFlight, u16> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.contains(&msg_id) {
+            return Err(message::ERR_DUPLICATE_ID);
+        }
+        if in_flight.len() >= self.max_in_flight {
+            return Err(message::ERR_TOO_MANY_IN_FLIGHT);
+        }
+        if let Some(bucket) = self.buckets.get(&proto_id) {
+            if !bucket.lock().unwrap().try_take(1) {
+                return Err(message::ERR_RATE_LIMITED);
+            }
+        }
+        in_flight.insert(msg_id);
+        Ok(InFlight {
+            msg_id,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> RouteDict {
+        RouteDict::from_routes(["chat.world.send", "area.player.move"]).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let routes = routes();
+        let limits = RequestLimits::parse("# chat\nchat.world.send 1 3\n", &routes, 4).unwrap();
+        assert_eq!(
+            limits.routes.get(&1),
+            Some(&RateLimit {
+                per_second: 1,
+                burst: 3
+            })
+        );
+        assert_eq!(limits.max_in_flight, 4);
+        assert!(matches!(
+            RequestLimits::parse("gacha.draw 1 1", &routes, 4),
+            Err(LimitsError::UnknownRoute(_))
+        ));
+        assert!(matches!(
+            RequestLimits::parse("chat.world.send 1", &routes, 4),
+            Err(LimitsError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_route_rate() {
+        let limits = RequestLimits::new(8).route(
+            1,
+            RateLimit {
+                per_second: 1,
+                burst: 2,
+            },
+        );
+        let limiter = RequestLimiter::new(&limits);
+        assert!(limiter.acquire(1, 1).is_ok());
+        assert!(limiter.acquire(1, 2).is_ok());
+        assert_eq!(
+            limiter.acquire(1, 3).unwrap_err(),
+            message::ERR_RATE_LIMITED
+        );
+        // other routes are not limited
+        assert!(limiter.acquire(2, 4).is_ok());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.acquire(1, 5).is_ok());
+        // a rejected request does not stay in flight
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let limiter = RequestLimiter::new(&RequestLimits::new(2));
+        let first = limiter.acquire(1, 1).unwrap();
+        let _second = limiter.acquire(2, 2).unwrap();
+        assert_eq!(
+            limiter.acquire(1, 3).unwrap_err(),
+            message::ERR_TOO_MANY_IN_FLIGHT
+        );
+        drop(first);
+        assert!(limiter.acquire(1, 3).is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_id() {
+        let limiter = RequestLimiter::new(&RequestLimits::new(8));
+        let first = limiter.acquire(1, 7).unwrap();
+        assert_eq!(
+            limiter.acquire(2, 7).unwrap_err(),
+            message::ERR_DUPLICATE_ID
+        );
+        // the id is free again once the response is sent
+        drop(first);
+        assert!(limiter.acquire(2, 7).is_ok());
+    }
+}
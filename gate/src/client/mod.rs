@@ -1,18 +1,35 @@
+pub mod limit;
 pub mod socket_client;
 
-use std::{
-    collections::hash_map,
-    sync::{Arc, Mutex},
-};
+use std::sync::Arc;
 
 use bytes::Bytes;
+use dashmap::DashMap;
+use orion::CloseReason;
 use tracing::error;
 
-#[derive(Clone)]
+struct ClientEntry<T> {
+    client: Arc<T>,
+    uid: Option<String>,
+}
+
+/// Clients by socket id, and socket ids by bound uid.
+///
+/// Both maps are sharded so lookups from different connections rarely contend. Writers
+/// always lock a socket entry before a uid entry, and a uid is only unbound by the socket
+/// it points to, so the two maps never disagree about a live binding.
 pub struct ClientManager<T: NetClient> {
-    client_map: Arc<Mutex<hash_map::HashMap<u32, Arc<T>>>>,
-    bound_clients: Arc<Mutex<hash_map::HashMap<String, u32>>>,
-    reverse_bound_clients: Arc<Mutex<hash_map::HashMap<u32, String>>>,
+    client_map: Arc<DashMap<u32, ClientEntry<T>>>,
+    bound_clients: Arc<DashMap<String, u32>>,
+}
+
+impl<T: NetClient> Clone for ClientManager<T> {
+    fn clone(&self) -> Self {
+        Self {
+            client_map: self.client_map.clone(),
+            bound_clients: self.bound_clients.clone(),
+        }
+    }
 }
 
 impl<T> ClientManager<T>
@@ -21,64 +38,92 @@ where
 {
     pub fn new() -> Self {
         Self {
-            client_map: Arc::new(Mutex::new(hash_map::HashMap::new())),
-            bound_clients: Arc::new(Mutex::new(hash_map::HashMap::new())),
-            reverse_bound_clients: Arc::new(Mutex::new(hash_map::HashMap::new())),
+            client_map: Arc::new(DashMap::new()),
+            bound_clients: Arc::new(DashMap::new()),
         }
     }
 
-    pub fn add_client(&mut self, id: u32, client: T) {
-        let mut map = self.client_map.lock().unwrap();
-        if map.contains_key(&id) {
-            error!("Client already exists: {}", id);
-            return;
+    pub fn add_client(&self, id: u32, client: T) {
+        match self.client_map.entry(id) {
+            dashmap::Entry::Occupied(_) => error!("Client already exists: {}", id),
+            dashmap::Entry::Vacant(entry) => {
+                entry.insert(ClientEntry {
+                    client: Arc::new(client),
+                    uid: None,
+                });
+            }
         }
-        map.insert(id, Arc::new(client));
     }
 
-    pub fn bind_connection(&self, uid: String, socket_id: u32) {
-        if let Some(_) = self.client_map.lock().unwrap().get(&socket_id) {
-            self.bound_clients
-                .lock()
-                .unwrap()
-                .insert(uid.clone(), socket_id);
-
-            self.reverse_bound_clients
-                .lock()
-                .unwrap()
-                .insert(socket_id, uid);
-        } else {
+    /// returns the socket the uid was bound to before, if it was another one
+    pub fn bind_connection(&self, uid: String, socket_id: u32) -> Option<u32> {
+        // holding the socket entry keeps remove_client out until the uid is bound
+        let Some(mut entry) = self.client_map.get_mut(&socket_id) else {
             error!("Failed to bind connection: socket not found {}", socket_id);
+            return None;
+        };
+        if let Some(old_uid) = entry.uid.replace(uid.clone()) {
+            if old_uid != uid {
+                self.bound_clients
+                    .remove_if(&old_uid, |_, bound| *bound == socket_id);
+            }
         }
+        self.bound_clients
+            .insert(uid, socket_id)
+            .filter(|previous| *previous != socket_id)
     }
 
     pub fn remove_client(&self, id: u32) -> Option<Arc<T>> {
-        let wrapper = self.client_map.lock().unwrap().remove(&id);
-        if let Some(_) = wrapper {
-            if let Some(uid) = self.reverse_bound_clients.lock().unwrap().remove(&id) {
-                self.bound_clients.lock().unwrap().remove(&uid);
-            }
+        let (_, entry) = self.client_map.remove(&id)?;
+        if let Some(uid) = entry.uid {
+            // the uid may have been bound to a newer socket in the meantime
+            self.bound_clients.remove_if(&uid, |_, bound| *bound == id);
         }
-        wrapper
+        Some(entry.client)
     }
 
     pub fn get_client(&self, id: u32) -> Option<Arc<T>> {
-        self.client_map.lock().unwrap().get(&id).cloned()
+        self.client_map.get(&id).map(|entry| entry.client.clone())
+    }
+
+    /// a snapshot, so callers can await on the clients without holding the lock
+    pub fn clients(&self) -> Vec<Arc<T>> {
+        self.client_map
+            .iter()
+            .map(|entry| entry.client.clone())
+            .collect()
     }
 
     pub fn get_client_by_uid(&self, uid: &str) -> Option<Arc<T>> {
-        if let Some(socket_id) = self.bound_clients.lock().unwrap().get(uid) {
-            self.client_map.lock().unwrap().get(socket_id).cloned()
-        } else {
-            None
-        }
+        let socket_id = *self.bound_clients.get(uid)?;
+        self.get_client(socket_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.client_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.client_map.is_empty()
+    }
+}
+
+impl<T> Default for ClientManager<T>
+where
+    T: NetClient,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 pub trait NetClient: Send + Sync {
     fn onopen(self: Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
     fn receive_msg(self: Arc<Self>, msg: Bytes) -> impl std::future::Future<Output = ()> + Send;
-    fn onclose(self: Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
+    fn onclose(
+        self: Arc<Self>,
+        reason: CloseReason,
+    ) -> impl std::future::Future<Output = ()> + Send;
     fn close(self: Arc<Self>) -> impl std::future::Future<Output = ()> + Send;
 }
 
@@ -88,39 +133,58 @@ mod tests {
 
     #[test]
     fn test_add_client() {
-        let mut client_manager = ClientManager::new();
+        let client_manager = ClientManager::new();
         let client_id = 1;
         let client = MockClient::new();
         let cm = client_manager.clone();
-        assert_eq!(cm.client_map.lock().unwrap().len(), 0);
+        assert_eq!(cm.client_map.len(), 0);
 
         client_manager.add_client(client_id, client.clone());
 
-        let map = cm.client_map.lock().unwrap();
-        assert_eq!(map.len(), 1);
+        assert_eq!(cm.client_map.len(), 1);
     }
 
     #[test]
     fn test_bind_connection() {
-        let mut client_manager = ClientManager::new();
+        let client_manager = ClientManager::new();
         let uid = "user1".to_string();
         let socket_id = 1;
 
         client_manager.add_client(socket_id, MockClient::new());
 
-        client_manager.bind_connection(uid.clone(), socket_id);
+        let previous = client_manager.bind_connection(uid.clone(), socket_id);
 
-        let bound_clients = client_manager.bound_clients.lock().unwrap();
-        let reverse_bound_clients = client_manager.reverse_bound_clients.lock().unwrap();
-        assert_eq!(bound_clients.len(), 1);
-        assert_eq!(bound_clients.get(&uid), Some(&socket_id));
-        assert_eq!(reverse_bound_clients.len(), 1);
-        assert_eq!(reverse_bound_clients.get(&socket_id), Some(&uid));
+        assert_eq!(previous, None);
+        assert_eq!(client_manager.bound_clients.len(), 1);
+        assert_eq!(
+            client_manager.bound_clients.get(&uid).map(|v| *v),
+            Some(socket_id)
+        );
+        let entry = client_manager.client_map.get(&socket_id).unwrap();
+        assert_eq!(entry.uid.as_deref(), Some(uid.as_str()));
+    }
+
+    #[test]
+    fn test_rebind_uid() {
+        let client_manager = ClientManager::new();
+        client_manager.add_client(1, MockClient::new());
+        client_manager.add_client(2, MockClient::new());
+
+        client_manager.bind_connection("user1".to_string(), 1);
+        let previous = client_manager.bind_connection("user1".to_string(), 2);
+        assert_eq!(previous, Some(1));
+
+        // the old socket closing must not unbind the new one
+        client_manager.remove_client(1);
+        assert!(client_manager.get_client_by_uid("user1").is_some());
+        client_manager.remove_client(2);
+        assert!(client_manager.get_client_by_uid("user1").is_none());
+        assert_eq!(client_manager.bound_clients.len(), 0);
     }
 
     #[test]
     fn test_remove_client() {
-        let mut client_manager = ClientManager::new();
+        let client_manager = ClientManager::new();
         let client_id = 1;
         let uid = "user2".to_string();
         let client = MockClient::new();
@@ -129,21 +193,15 @@ mod tests {
 
         client_manager.remove_client(client_id);
 
-        let map = client_manager.client_map.lock().unwrap();
-        assert_eq!(map.len(), 0);
-
-        let bound_clients = client_manager.bound_clients.lock().unwrap();
-        assert_eq!(bound_clients.len(), 0);
-        assert_eq!(bound_clients.get(&uid), None);
-
-        let reverse_bound_clients = client_manager.reverse_bound_clients.lock().unwrap();
-        assert_eq!(reverse_bound_clients.len(), 0);
-        assert_eq!(reverse_bound_clients.get(&client_id), None);
+        assert_eq!(client_manager.client_map.len(), 0);
+        assert_eq!(client_manager.bound_clients.len(), 0);
+        assert!(client_manager.bound_clients.get(&uid).is_none());
     }
 
     #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn test_get_client() {
-        let mut client_manager = ClientManager::new();
+        let client_manager = ClientManager::new();
         let client_id = 1;
         let client = MockClient::new();
         client_manager.add_client(client_id, client.clone());
@@ -158,6 +216,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::redundant_pattern_matching)]
     fn test_get_client_by_uid() {
         let client_manager = ClientManager::<MockClient>::new();
         let uid = "user1".to_string();
@@ -173,6 +232,46 @@ mod tests {
         assert!(!has);
     }
 
+    #[test]
+    fn test_clients() {
+        let client_manager = ClientManager::new();
+        client_manager.add_client(1, MockClient::new());
+        client_manager.add_client(2, MockClient::new());
+
+        let clients = client_manager.clients();
+        client_manager.remove_client(1);
+
+        assert_eq!(clients.len(), 2);
+        assert_eq!(client_manager.clients().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_bind_remove() {
+        let client_manager = ClientManager::new();
+        let threads: Vec<_> = (0..8u32)
+            .map(|t| {
+                let cm = client_manager.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        let id = t * 1000 + i;
+                        cm.add_client(id, MockClient::new());
+                        cm.bind_connection(format!("user{}", i % 50), id);
+                        if i % 2 == 0 {
+                            cm.remove_client(id);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for entry in client_manager.bound_clients.iter() {
+            let client = client_manager.client_map.get(entry.value()).unwrap();
+            assert_eq!(client.uid.as_deref(), Some(entry.key().as_str()));
+        }
+    }
+
     #[derive(Clone)]
     struct MockClient;
 
@@ -183,7 +282,7 @@ mod tests {
     }
 
     impl NetClient for MockClient {
-        async fn receive_msg(self: Arc<Self>, msg: Bytes) {
+        async fn receive_msg(self: Arc<Self>, _msg: Bytes) {
             // Mock implementation
         }
 
@@ -191,7 +290,7 @@ mod tests {
             todo!()
         }
 
-        async fn onclose(self: Arc<Self>) {
+        async fn onclose(self: Arc<Self>, _reason: CloseReason) {
             todo!()
         }
 
@@ -1,60 +1,177 @@
-use std::{
-    sync::{atomic::AtomicU8, Arc},
-    time::Duration,
-};
+use std::sync::{atomic::AtomicU8, Arc, Mutex, OnceLock};
 
-use bytes::{BufMut, Bytes, BytesMut};
-use orion::SocketHandle;
-use tokio::{select, sync::mpsc, time::sleep};
+use bytes::Bytes;
+use orion::{
+    app,
+    channel::ChannelClient,
+    cluster::routing::RouteContext,
+    codec::CodecKind,
+    envelope::{self, Envelope},
+    nats_client::RetryPolicy,
+    session::Session,
+    CloseReason, SocketHandle,
+};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{error, info, warn};
 
 use crate::{
+    auth::AuthRequest,
     global,
-    protocol::{message, packet},
+    protocol::{
+        compression::MessageCompression,
+        crypto::{EncryptionMode, KeyExchange, PacketCipher, Role, ServerKey},
+        handshake::{self, HandshakeRequest, HandshakeResponse},
+        message::{self, DecodeError, IdFormat, MsgType},
+        packet,
+        push::{PushError, SharedPush},
+    },
 };
 
-use super::NetClient;
+use super::{limit::RequestLimiter, NetClient};
 
 const WAIT_FOR_HANDSHAKE: u8 = 0;
 const WAIT_FOR_HANDSHAKE_ACK: u8 = 1;
 const READY: u8 = 2;
 
-const HEARTBEAT_INTERVAL: u8 = 20;
+/// seconds, connections are closed after two intervals without traffic
+pub const HEARTBEAT_INTERVAL: u8 = 20;
 
 #[derive(Debug, Clone)]
 pub struct Client {
     socket: SocketHandle,
     state: Arc<AtomicU8>,
-    heartbeat_recved: mpsc::Sender<()>,
+    uid: Arc<OnceLock<String>>,
+    session: Arc<Mutex<Session>>,
+    limiter: Arc<RequestLimiter>,
+    compression: Arc<OnceLock<MessageCompression>>,
+    cipher: Arc<OnceLock<PacketCipher>>,
+    id_format: Arc<OnceLock<IdFormat>>,
+    codec: Arc<OnceLock<CodecKind>>,
     dead: CancellationToken,
 }
 
 impl NetClient for Client {
     async fn receive_msg(self: Arc<Self>, msg: Bytes) {
-        let (packet_type, decoded_body) = packet::decode(msg);
+        let (packet_type, decoded_body) = match packet::decode(msg) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.reject_malformed(e).await;
+                return;
+            }
+        };
         match packet_type {
             packet::PacketType::Handshake => {
                 if self.state.load(std::sync::atomic::Ordering::SeqCst) != WAIT_FOR_HANDSHAKE {
                     return;
                 }
-                let uid_len = decoded_body[0];
-                let uid_bytes = decoded_body.slice(1..(uid_len + 1) as usize);
-                let uid = String::from_utf8(uid_bytes.to_vec());
-                match uid {
-                    Ok(uid) => {
-                        // TODO: 剔除重复登录用户
-                        global::client_manager_copy().bind_connection(uid, self.socket.id());
+                let req = match HandshakeRequest::decode(&decoded_body) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        error!("Failed to parse handshake: {}", e);
+                        self.reject_handshake(handshake::CODE_BAD_REQUEST).await;
+                        return;
                     }
+                };
+                if let Err(code) = req.check_version() {
+                    warn!(
+                        "Rejected client with protocol version {}",
+                        req.protocol_version
+                    );
+                    self.reject_handshake(code).await;
+                    return;
+                }
+                let codec = match req.codec.as_deref() {
+                    None => global::codecs()[0],
+                    Some(name) => match CodecKind::from_name(name)
+                        .filter(|codec| global::codecs().contains(codec))
+                    {
+                        Some(codec) => codec,
+                        None => {
+                            warn!("Rejected client asking for codec {}", name);
+                            self.reject_handshake(handshake::CODE_UNSUPPORTED_CODEC)
+                                .await;
+                            return;
+                        }
+                    },
+                };
+                let key_exchange = match (&req.public_key, global::encryption()) {
+                    (None, EncryptionMode::Required) => {
+                        warn!("Rejected unencrypted client {}", self.socket.peer_addr());
+                        self.reject_handshake(handshake::CODE_ENCRYPTION_REQUIRED)
+                            .await;
+                        return;
+                    }
+                    (Some(peer_key), EncryptionMode::Optional | EncryptionMode::Required) => {
+                        let exchange = KeyExchange::new();
+                        let public_key = exchange.public_key();
+                        let finished = match global::server_key() {
+                            Some(key) => exchange.finish_server(peer_key, key),
+                            None => exchange.finish(peer_key, Role::Server),
+                        };
+                        match finished {
+                            Ok(cipher) => Some((public_key, cipher)),
+                            Err(e) => {
+                                warn!(
+                                    "Failed key exchange with {}: {}",
+                                    self.socket.peer_addr(),
+                                    e
+                                );
+                                self.reject_handshake(handshake::CODE_BAD_REQUEST).await;
+                                return;
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                let auth_req = AuthRequest {
+                    token: &req.token,
+                    addr: self.socket.peer_addr(),
+                    handshake: &req,
+                };
+                let uid = match global::authenticator().authenticate(auth_req).await {
+                    Ok(uid) => uid,
                     Err(e) => {
-                        error!("Failed to parse uid: {}", e);
-                        self.socket.close().await;
+                        warn!("Failed to authenticate {}: {}", self.socket.peer_addr(), e);
+                        self.send_error(handshake::CODE_AUTH_FAILED).await;
                         return;
                     }
+                };
+                // TODO: 剔除重复登录用户
+                let _ = self.uid.set(uid.clone());
+                global::client_manager_copy().bind_connection(uid, self.socket.id());
+
+                let compression = global::compression().negotiate(&req.compression);
+                if let Some(compression) = compression {
+                    let _ = self.compression.set(compression);
                 }
-                let mut send_bytes = BytesMut::new();
-                send_bytes.put_u8(HEARTBEAT_INTERVAL); // heartbeat interval
-                let packet = packet::encode(packet::PacketType::Handshake, send_bytes.freeze());
+                let _ = self.codec.set(codec);
+                let _ = self
+                    .id_format
+                    .set(IdFormat::for_version(req.protocol_version));
+                let public_key = key_exchange.map(|(public_key, cipher)| {
+                    let _ = self.cipher.set(cipher);
+                    public_key
+                });
+                let server_key = public_key
+                    .as_ref()
+                    .and(global::server_key())
+                    .map(ServerKey::public_key);
+                let routes = global::routes();
+                let res = HandshakeResponse {
+                    code: handshake::CODE_OK,
+                    // the client's version is in the accepted range, speak it back
+                    protocol_version: req.protocol_version,
+                    heartbeat: HEARTBEAT_INTERVAL,
+                    route_version: routes.version(),
+                    routes: (req.route_version != Some(routes.version()))
+                        .then(|| routes.routes().map(String::from).collect()),
+                    compression: compression.map(|c| c.codec.name().to_string()),
+                    compression_threshold: compression.map_or(0, |c| c.threshold),
+                    public_key,
+                    server_key,
+                    codec: codec.name().to_string(),
+                };
+                let packet = packet::encode(packet::PacketType::Handshake, res.encode());
                 self.state
                     .store(WAIT_FOR_HANDSHAKE_ACK, std::sync::atomic::Ordering::SeqCst);
                 self.socket.send(packet).await;
@@ -63,10 +180,19 @@ impl NetClient for Client {
                 if self.state.load(std::sync::atomic::Ordering::SeqCst) != WAIT_FOR_HANDSHAKE_ACK {
                     return;
                 }
+                // on encrypted connections the ack proves the client holds the same keys
+                if let Err(e) = packet::open(decoded_body, self.cipher()) {
+                    warn!(
+                        "Failed to confirm keys with {}: {}",
+                        self.socket.peer_addr(),
+                        e
+                    );
+                    self.socket.close().await;
+                    return;
+                }
                 self.state.store(READY, std::sync::atomic::Ordering::SeqCst);
             }
             packet::PacketType::Heartbeat => {
-                let _ = self.heartbeat_recved.send(()).await;
                 let packet = packet::encode(packet::PacketType::Heartbeat, Bytes::new());
                 self.socket.send(packet).await;
             }
@@ -74,17 +200,120 @@ impl NetClient for Client {
                 if self.state.load(std::sync::atomic::Ordering::SeqCst) != READY {
                     return;
                 }
-                let (msg_type, proto_id, id, data) = message::decode(decoded_body);
+                let decoded_body = match packet::open(decoded_body, self.cipher()) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!(
+                            "Failed to open packet from {}: {}",
+                            self.socket.peer_addr(),
+                            e
+                        );
+                        self.socket.close().await;
+                        return;
+                    }
+                };
+                let compressed = message::is_compressed(&decoded_body);
+                let (msg_type, proto_id, id, data) =
+                    match message::decode(self.id_format(), decoded_body) {
+                        Ok(decoded) => decoded,
+                        Err(e) => {
+                            self.reject_malformed(e).await;
+                            return;
+                        }
+                    };
+                let data = match (compressed, self.compression()) {
+                    (false, _) => data,
+                    (true, None) => {
+                        self.reject_malformed(DecodeError::UnexpectedCompression)
+                            .await;
+                        return;
+                    }
+                    (true, Some(c)) => match c.codec.decompress(&data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            warn!(
+                                "Failed to decompress message from {}: {}",
+                                self.socket.peer_addr(),
+                                e
+                            );
+                            if matches!(msg_type, MsgType::Request) {
+                                self.send_error_response(id, message::ERR_BAD_COMPRESSION)
+                                    .await;
+                            }
+                            return;
+                        }
+                    },
+                };
+                match msg_type {
+                    MsgType::Request => {
+                        let in_flight = match self.limiter.acquire(proto_id, id) {
+                            Ok(in_flight) => in_flight,
+                            Err(code) => {
+                                warn!(
+                                    "Rejected request {} from {}: code {}",
+                                    proto_id,
+                                    self.socket.peer_addr(),
+                                    code
+                                );
+                                self.send_error_response(id, code).await;
+                                return;
+                            }
+                        };
+                        // don't hold up the read loop while the backend answers
+                        tokio::spawn(async move {
+                            let timeout = global::request_limits().request_timeout();
+                            let forward = self.forward(proto_id, Some(id), data);
+                            let result = match tokio::time::timeout(timeout, forward).await {
+                                Ok(result) => result,
+                                Err(_) => {
+                                    warn!(
+                                        "Request {} from {} timed out",
+                                        proto_id,
+                                        self.socket.peer_addr()
+                                    );
+                                    Err(message::ERR_TIMEOUT)
+                                }
+                            };
+                            if let Err(code) = result {
+                                self.send_error_response(id, code).await;
+                            }
+                            // the id may be reused once the client has its response
+                            drop(in_flight);
+                        });
+                    }
+                    MsgType::Notify => {
+                        let _ = self.forward(proto_id, None, data).await;
+                    }
+                    MsgType::Response | MsgType::Push => {
+                        warn!("Unexpected message type from {}", self.socket.peer_addr());
+                    }
+                }
+            }
+            // only the gate sends these
+            packet::PacketType::Kick | packet::PacketType::Error => {
+                self.reject_malformed(DecodeError::UnknownType(packet_type as u8))
+                    .await;
             }
-            packet::PacketType::Kick => todo!(),
-            packet::PacketType::Error => todo!(),
         }
     }
 
     async fn onopen(self: Arc<Self>) {}
 
-    async fn onclose(self: Arc<Self>) {
+    async fn onclose(self: Arc<Self>, reason: CloseReason) {
+        info!("Client {} closed: {:?}", self.socket.peer_addr(), reason);
         // TODO: 把此用户相关的数据从缓冲或者其他服务器清理
+        if let Some(uid) = self.uid() {
+            // a newer connection of the same player keeps its channels
+            let current = global::client_manager_copy()
+                .get_client_by_uid(uid)
+                .is_some_and(|client| client.socket.id() == self.socket.id());
+            if current {
+                // every gate holds the memberships, this one included
+                ChannelClient::new(global::nats().clone())
+                    .leave_all(uid)
+                    .await;
+            }
+        }
         self.dead.cancel();
     }
 
@@ -96,30 +325,496 @@ impl NetClient for Client {
 }
 
 impl Client {
-    pub fn new(socket: SocketHandle) -> Self {
-        let (tx, mut rx) = mpsc::channel(1);
+    async fn reject_handshake(&self, code: u16) {
+        let res = HandshakeResponse::reject(code);
+        let packet = packet::encode(packet::PacketType::Handshake, res.encode());
+        self.socket.send(packet).await;
+        self.socket.close().await;
+    }
 
-        let s = socket.clone();
-        tokio::spawn(async move {
-            loop {
-                select! {
-                        _ = sleep(Duration::from_secs((HEARTBEAT_INTERVAL * 2).into())) => {
-                            s.close().await;
-                            break;
-                        }
-                        v = rx.recv() => {
-                            if let None = v {
-                                break;
-                            }
-                        }
-                }
-            }
-        });
+    /// the client broke the protocol, tell it why and drop it
+    async fn reject_malformed(&self, e: DecodeError) {
+        warn!("Closing {}: {}", self.socket.peer_addr(), e);
+        self.send_error(handshake::CODE_BAD_REQUEST).await;
+    }
+
+    async fn send_error(&self, code: u16) {
+        let res = HandshakeResponse::reject(code);
+        let packet = packet::encode(packet::PacketType::Error, res.encode());
+        self.socket.send(packet).await;
+        self.socket.close().await;
+    }
+
+    pub fn new(socket: SocketHandle) -> Self {
         Client {
             socket,
             state: Arc::new(AtomicU8::new(0)),
-            heartbeat_recved: tx,
+            uid: Arc::new(OnceLock::new()),
+            session: Arc::new(Mutex::new(Session::default())),
+            limiter: Arc::new(RequestLimiter::new(global::request_limits())),
+            compression: Arc::new(OnceLock::new()),
+            cipher: Arc::new(OnceLock::new()),
+            id_format: Arc::new(OnceLock::new()),
+            codec: Arc::new(OnceLock::new()),
             dead: CancellationToken::new(),
         }
     }
+
+    pub async fn send(&self, pkt: Bytes) {
+        self.socket.send(pkt).await;
+    }
+
+    /// wraps the message in a Data packet, sealed on encrypted connections
+    pub async fn send_message(&self, msg: Bytes) {
+        let pkt = packet::encode_sealed(packet::PacketType::Data, msg, self.cipher());
+        self.socket.send(pkt).await;
+    }
+
+    /// never waits, a client that does not keep up misses the push
+    pub fn send_push(&self, push: &mut SharedPush) -> Result<(), PushError> {
+        let codec = self.codec();
+        let compression = self.compression();
+        let pkt = match self.cipher() {
+            Some(cipher) => {
+                let msg = push
+                    .message(codec, compression)
+                    .ok_or(PushError::NoPayload(codec))?;
+                packet::encode_sealed(packet::PacketType::Data, msg, Some(cipher))
+            }
+            None => push
+                .packet(codec, compression)
+                .ok_or(PushError::NoPayload(codec))?,
+        };
+        match self.socket.try_send(pkt) {
+            true => Ok(()),
+            false => Err(PushError::Full),
+        }
+    }
+
+    fn cipher(&self) -> Option<&PacketCipher> {
+        self.cipher.get()
+    }
+
+    /// the handshake is complete
+    pub fn is_ready(&self) -> bool {
+        self.state.load(std::sync::atomic::Ordering::SeqCst) == READY
+    }
+
+    /// set once the handshake is authenticated
+    pub fn uid(&self) -> Option<&str> {
+        self.uid.get().map(String::as_str)
+    }
+
+    /// a copy of the session
+    pub fn session(&self) -> Session {
+        self.session.lock().unwrap().clone()
+    }
+
+    /// agreed at handshake, `None` if the client sends and receives raw payloads
+    pub fn compression(&self) -> Option<&MessageCompression> {
+        self.compression.get()
+    }
+
+    pub fn update_session<R>(&self, f: impl FnOnce(&mut Session) -> R) -> R {
+        f(&mut self.session.lock().unwrap())
+    }
+
+    /// the payload codec agreed at handshake
+    pub fn codec(&self) -> CodecKind {
+        self.codec.get().copied().unwrap_or_default()
+    }
+
+    fn id_format(&self) -> IdFormat {
+        self.id_format.get().copied().unwrap_or_default()
+    }
+
+    async fn send_error_response(&self, msg_id: u32, code: u16) {
+        let msg =
+            message::encode_error(self.id_format(), msg_id, code, message::error_message(code));
+        self.send_message(msg).await;
+    }
+
+    async fn send_error_push(&self, proto_id: u16, code: u16) {
+        let msg = message::encode_error_push(proto_id, code, message::error_message(code));
+        self.send_message(msg).await;
+    }
+
+    /// the error is the code of the error response owed to the client for a request
+    async fn forward(&self, proto_id: u16, msg_id: Option<u32>, data: Bytes) -> Result<(), u16> {
+        let Some(route) = global::routes().route(proto_id) else {
+            warn!(
+                "Unknown protocol id {} from {}",
+                proto_id,
+                self.socket.peer_addr()
+            );
+            return Err(message::ERR_UNKNOWN_ROUTE);
+        };
+        let uid = self.uid().unwrap_or_default().to_string();
+        let mut session = self.session();
+        let ctx = RouteContext {
+            uid: &uid,
+            route,
+            session: &session,
+        };
+        let target = match global::routing_table().resolve(&ctx) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!("Failed to route {}: {}", route, e);
+                return Err(message::ERR_NO_SERVER);
+            }
+        };
+        if let Some(binding) = &target.binding {
+            if let Some(lost) = binding.lost {
+                warn!(
+                    "Server {} {} of {} is gone, moving to {}",
+                    target.server_type, lost, uid, binding.server_id
+                );
+                self.send_error_push(proto_id, message::ERR_SERVER_LOST)
+                    .await;
+            }
+            // the backend sees the binding in this very message
+            let bound = session
+                .set(binding.key.clone(), binding.server_id)
+                .and_then(|_| {
+                    self.update_session(|s| s.set(binding.key.clone(), binding.server_id))
+                });
+            if let Err(e) = bound {
+                error!("Failed to bind {} to {}: {}", uid, binding.server_id, e);
+                return Err(message::ERR_NO_SERVER);
+            }
+        }
+        let subject = target.subject();
+        let envelope = Envelope {
+            gate_id: app().uuid(),
+            uid,
+            route: route.to_string(),
+            msg_id,
+            session,
+            codec: self.codec(),
+            payload: data,
+        };
+        let Some(id) = msg_id else {
+            global::nats()
+                .publish_with_headers(subject, envelope.headers(), envelope.encode())
+                .await;
+            return Ok(());
+        };
+        // a request that timed out may still have run, handlers need not be idempotent
+        let policy = RetryPolicy::none(global::request_limits().request_timeout());
+        let reply = global::nats()
+            .try_request_with_headers(subject, envelope.headers(), envelope.encode(), &policy)
+            .await;
+        match reply {
+            Ok(reply) => {
+                if let Some(reason) = envelope::reply_error(reply.headers.as_ref()) {
+                    warn!("Backend failed {}: {}", route, reason);
+                    return Err(message::ERR_BACKEND_FAILED);
+                }
+                let msg = message::encode_compressed(
+                    self.id_format(),
+                    MsgType::Response,
+                    0,
+                    id,
+                    reply.payload,
+                    self.compression(),
+                );
+                self.send_message(msg).await;
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to forward {}: {}", route, e);
+                Err(message::ERR_BACKEND_FAILED)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use orion::{
+        broadcast::PushPayload,
+        channel::{ChannelCommand, CHANNEL_SUBJECT},
+        cluster::{registry::ServerInfo, routing::binding_key},
+        nats_client::Concurrency,
+        session::SessionService,
+    };
+    use tokio::{
+        io::AsyncReadExt,
+        net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        channel::push_to,
+        client::ClientManager,
+        protocol::{compression::Compression, message::ErrorBody},
+        service::session::GateSessionService,
+    };
+
+    const MOVE: &str = "area.player.move";
+
+    /// a client on a real socket, the other end reads what the gate sends
+    async fn connect() -> (Client, OwnedReadHalf) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let (_, writer) = stream.into_split();
+        let client = Client::new(SocketHandle::new(writer, addr, CancellationToken::new()));
+        (client, peer.into_split().0)
+    }
+
+    /// the message in the next Data packet
+    async fn read_message(peer: &mut OwnedReadHalf) -> (MsgType, u16, Bytes) {
+        let mut head = [0u8; 4];
+        peer.read_exact(&mut head).await.unwrap();
+        let len = (head[1] as usize) << 16 | (head[2] as usize) << 8 | head[3] as usize;
+        let mut body = vec![0u8; len];
+        peer.read_exact(&mut body).await.unwrap();
+        let (msg_type, proto_id, _, data) =
+            message::decode(IdFormat::default(), Bytes::from(body)).unwrap();
+        (msg_type, proto_id, data)
+    }
+
+    #[tokio::test]
+    async fn test_sticky_binding() {
+        global::init_for_tests();
+        let nats = global::nats();
+        let replier = nats.clone();
+        // each area server answers with its own subject
+        let _area = nats
+            .subscribe_async(
+                "server.area.*".to_string(),
+                Concurrency::Bounded(4),
+                move |msg| {
+                    let nats = replier.clone();
+                    async move {
+                        let reply = msg.reply.unwrap().to_string();
+                        nats.publish(reply, Bytes::from(msg.subject.to_string()))
+                            .await;
+                    }
+                },
+            )
+            .await
+            .unwrap();
+        let registry = global::routing_table().registry();
+        registry.insert(ServerInfo::new(1, "area"));
+        registry.insert(ServerInfo::new(2, "area"));
+        let proto_id = global::routes().id(MOVE).unwrap();
+
+        let (client, mut peer) = connect().await;
+        client.uid.set("u1".to_string()).unwrap();
+        let client_mgr = ClientManager::new();
+        client_mgr.add_client(client.socket.id(), client.clone());
+        client_mgr.bind_connection("u1".to_string(), client.socket.id());
+        let key = binding_key("area");
+
+        // the first message binds the player to the server the hash picks
+        client
+            .forward(proto_id, Some(1), Bytes::new())
+            .await
+            .unwrap();
+        let first: u32 = client.session().get(&key).unwrap();
+        let (msg_type, _, data) = read_message(&mut peer).await;
+        assert_eq!(msg_type as u8, MsgType::Response as u8);
+        assert_eq!(data, envelope::instance_subject("area", first));
+
+        // a backend moves the player to the other server
+        let other = 3 - first;
+        let service = GateSessionService::new(client_mgr);
+        assert!(!service.bind("u1".to_string(), "area".to_string(), 9).await);
+        assert!(
+            service
+                .bind("u1".to_string(), "area".to_string(), other)
+                .await
+        );
+        client
+            .forward(proto_id, Some(2), Bytes::new())
+            .await
+            .unwrap();
+        let (_, _, data) = read_message(&mut peer).await;
+        assert_eq!(data, envelope::instance_subject("area", other));
+
+        // that server leaves, the player is told and moved back
+        registry.remove("area", other);
+        client
+            .forward(proto_id, Some(3), Bytes::new())
+            .await
+            .unwrap();
+        let (msg_type, pushed_id, data) = read_message(&mut peer).await;
+        assert_eq!(msg_type as u8, MsgType::Push as u8);
+        assert_eq!(pushed_id, proto_id);
+        let body: ErrorBody = serde_json::from_slice(&data).unwrap();
+        assert_eq!(body.code, message::ERR_SERVER_LOST);
+        let (_, _, data) = read_message(&mut peer).await;
+        assert_eq!(data, envelope::instance_subject("area", first));
+        assert_eq!(client.session().get::<u32>(&key), Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_bad_compressed_request() {
+        global::init_for_tests();
+        let proto_id = global::routes().id(MOVE).unwrap();
+        let request = |id| {
+            let mut msg = message::encode(
+                IdFormat::default(),
+                MsgType::Request,
+                proto_id,
+                id,
+                Bytes::from_static(b"not zstd"),
+            )
+            .to_vec();
+            msg[0] |= message::COMPRESSED_FLAG;
+            packet::encode(packet::PacketType::Data, Bytes::from(msg))
+        };
+
+        // data that does not decompress fails the request
+        let (client, mut peer) = connect().await;
+        let client = Arc::new(client);
+        client
+            .state
+            .store(READY, std::sync::atomic::Ordering::SeqCst);
+        let compression = MessageCompression {
+            codec: Compression::Zstd,
+            threshold: 0,
+        };
+        client.compression.set(compression).unwrap();
+        client.clone().receive_msg(request(1)).await;
+        let (msg_type, _, data) = read_message(&mut peer).await;
+        assert_eq!(msg_type as u8, MsgType::Response as u8);
+        let body: ErrorBody = serde_json::from_slice(&data).unwrap();
+        assert_eq!(body.code, message::ERR_BAD_COMPRESSION);
+
+        // a compressed message without negotiated compression breaks the protocol
+        let (client, mut peer) = connect().await;
+        let client = Arc::new(client);
+        client
+            .state
+            .store(READY, std::sync::atomic::Ordering::SeqCst);
+        client.clone().receive_msg(request(1)).await;
+        let mut head = [0u8; 4];
+        peer.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], packet::PacketType::Error as u8);
+    }
+
+    #[tokio::test]
+    async fn test_close_leaves_channels() {
+        global::init_for_tests();
+        let client_mgr = global::client_manager_copy();
+        let connect_as = |uid: &'static str| {
+            let client_mgr = client_mgr.clone();
+            async move {
+                let (client, _) = connect().await;
+                client.uid.set(uid.to_string()).unwrap();
+                client_mgr.add_client(client.socket.id(), client.clone());
+                client_mgr.bind_connection(uid.to_string(), client.socket.id());
+                Arc::new(client)
+            }
+        };
+        let (sender, mut left) = tokio::sync::mpsc::unbounded_channel();
+        global::nats()
+            .subscribe(CHANNEL_SUBJECT.to_string(), move |msg| {
+                if let Ok((ChannelCommand::LeaveAll { uid }, _)) =
+                    ChannelCommand::decode(msg.payload)
+                {
+                    let _ = sender.send(uid);
+                }
+            })
+            .await
+            .unwrap();
+        async fn next_left(left: &mut tokio::sync::mpsc::UnboundedReceiver<String>) -> String {
+            loop {
+                let uid = tokio::time::timeout(Duration::from_secs(1), left.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                // other tests publish on the same subject
+                if uid.starts_with("close.") {
+                    return uid;
+                }
+            }
+        }
+
+        // every gate is told to drop the player from its channels
+        let client = connect_as("close.player").await;
+        client.onclose(CloseReason::Eof).await;
+        assert_eq!(next_left(&mut left).await, "close.player");
+
+        // the old connection of a player who logged in again leaves the channels alone
+        let old = connect_as("close.relogin").await;
+        let _new = connect_as("close.relogin").await;
+        old.onclose(CloseReason::Eof).await;
+        let after = connect_as("close.after").await;
+        after.onclose(CloseReason::Eof).await;
+        assert_eq!(next_left(&mut left).await, "close.after");
+    }
+
+    #[tokio::test]
+    async fn test_push_per_codec() {
+        global::init_for_tests();
+        let payload = PushPayload::new()
+            .with(CodecKind::Json, Bytes::from(r#"{"text":"hi"}"#))
+            .with(CodecKind::Protobuf, Bytes::from("\n\x02hi"));
+        let mut push = SharedPush::new(7, payload.clone());
+        for codec in CodecKind::ALL {
+            let (client, mut peer) = connect().await;
+            client.codec.set(codec).unwrap();
+            client.send_push(&mut push).unwrap();
+            let (msg_type, proto_id, data) = read_message(&mut peer).await;
+            assert_eq!(msg_type as u8, MsgType::Push as u8);
+            assert_eq!(proto_id, 7);
+            assert_eq!(Some(&data), payload.get(codec));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_to_slow_client() {
+        global::init_for_tests();
+        // the peer never reads
+        let (client, _peer) = connect().await;
+        let (reading, mut peer) = connect().await;
+        let payload = PushPayload::new().with(CodecKind::Json, Bytes::from(vec![0u8; 1 << 20]));
+        let mut push = SharedPush::new(7, payload.clone());
+        let mut full = false;
+        for _ in 0..1000 {
+            match client.send_push(&mut push) {
+                Ok(()) => tokio::task::yield_now().await,
+                Err(e) => {
+                    assert_eq!(e, PushError::Full);
+                    full = true;
+                    break;
+                }
+            }
+        }
+        assert!(full);
+
+        // the others still get theirs
+        let clients = [Arc::new(client), Arc::new(reading)];
+        assert_eq!(push_to(clients, SharedPush::new(7, payload), "test"), 1);
+        let (msg_type, _, data) = read_message(&mut peer).await;
+        assert_eq!(msg_type as u8, MsgType::Push as u8);
+        assert_eq!(data.len(), 1 << 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forward_timeout_not_retried() {
+        global::init_for_tests();
+        let received = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = received.clone();
+        // a backend that takes the request but never answers
+        global::nats()
+            .subscribe("server.chat".to_string(), move |_| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            })
+            .await
+            .unwrap();
+        let (client, _peer) = connect().await;
+        let proto_id = global::routes().id("chat.world.send").unwrap();
+        let result = client.forward(proto_id, Some(1), Bytes::new()).await;
+        assert_eq!(result, Err(message::ERR_BACKEND_FAILED));
+        assert_eq!(received.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
 }
@@ -1,13 +1,34 @@
 use std::sync::OnceLock;
 
-use orion::nats_client::NatsClient;
+use orion::{
+    cluster::routing::RoutingTable, codec::CodecKind, nats_client::NatsClient, route::RouteDict,
+    TcpMetrics,
+};
 use redis::aio::ConnectionManager;
 
-use crate::client::{socket_client::Client, ClientManager};
+use crate::{
+    auth::Authenticator,
+    channel::ChannelManager,
+    client::{limit::RequestLimits, socket_client::Client, ClientManager},
+    protocol::{
+        compression::CompressionConfig,
+        crypto::{EncryptionMode, ServerKey},
+    },
+};
 
 static REDIS: OnceLock<ConnectionManager> = OnceLock::new();
 static NATS: OnceLock<NatsClient> = OnceLock::new();
 static CLIENTMANAGER: OnceLock<ClientManager<Client>> = OnceLock::new();
+static ROUTES: OnceLock<RouteDict> = OnceLock::new();
+static CHANNELMANAGER: OnceLock<ChannelManager> = OnceLock::new();
+static REQUESTLIMITS: OnceLock<RequestLimits> = OnceLock::new();
+static COMPRESSION: OnceLock<CompressionConfig> = OnceLock::new();
+static ENCRYPTION: OnceLock<EncryptionMode> = OnceLock::new();
+static SERVERKEY: OnceLock<Option<ServerKey>> = OnceLock::new();
+static CODECS: OnceLock<Vec<CodecKind>> = OnceLock::new();
+static ROUTINGTABLE: OnceLock<RoutingTable> = OnceLock::new();
+static TCPMETRICS: OnceLock<TcpMetrics> = OnceLock::new();
+static AUTHENTICATOR: OnceLock<Box<dyn Authenticator>> = OnceLock::new();
 
 pub fn set_redis(client: ConnectionManager) {
     REDIS.get_or_init(|| client);
@@ -35,3 +56,111 @@ pub fn client_manager_copy() -> ClientManager<Client> {
         .expect("ClientManager not registered")
         .clone()
 }
+
+pub fn set_routes(routes: RouteDict) {
+    ROUTES.get_or_init(|| routes);
+}
+
+pub fn routes() -> &'static RouteDict {
+    ROUTES.get().expect("Routes not registered")
+}
+
+pub fn set_authenticator(authenticator: impl Authenticator + 'static) {
+    AUTHENTICATOR.get_or_init(|| Box::new(authenticator));
+}
+
+pub fn authenticator() -> &'static dyn Authenticator {
+    AUTHENTICATOR
+        .get()
+        .expect("Authenticator not registered")
+        .as_ref()
+}
+
+pub fn set_channel_manager(mgr: ChannelManager) {
+    CHANNELMANAGER.get_or_init(|| mgr);
+}
+
+pub fn channel_manager() -> &'static ChannelManager {
+    CHANNELMANAGER.get().expect("ChannelManager not registered")
+}
+
+pub fn set_request_limits(limits: RequestLimits) {
+    REQUESTLIMITS.get_or_init(|| limits);
+}
+
+pub fn request_limits() -> &'static RequestLimits {
+    REQUESTLIMITS.get().expect("RequestLimits not registered")
+}
+
+pub fn set_compression(config: CompressionConfig) {
+    COMPRESSION.get_or_init(|| config);
+}
+
+pub fn compression() -> &'static CompressionConfig {
+    COMPRESSION.get().expect("Compression not registered")
+}
+
+pub fn set_encryption(mode: EncryptionMode) {
+    ENCRYPTION.get_or_init(|| mode);
+}
+
+pub fn encryption() -> EncryptionMode {
+    *ENCRYPTION.get().expect("Encryption not registered")
+}
+
+/// `None` leaves encrypted connections unauthenticated
+pub fn set_server_key(key: Option<ServerKey>) {
+    SERVERKEY.get_or_init(|| key);
+}
+
+pub fn server_key() -> Option<&'static ServerKey> {
+    SERVERKEY.get().expect("ServerKey not registered").as_ref()
+}
+
+/// the first one is the default for clients that do not ask for a codec
+pub fn set_codecs(codecs: Vec<CodecKind>) {
+    assert!(!codecs.is_empty(), "At least one codec must be enabled");
+    CODECS.get_or_init(|| codecs);
+}
+
+pub fn codecs() -> &'static [CodecKind] {
+    CODECS.get().expect("Codecs not registered")
+}
+
+pub fn set_routing_table(table: RoutingTable) {
+    ROUTINGTABLE.get_or_init(|| table);
+}
+
+pub fn routing_table() -> &'static RoutingTable {
+    ROUTINGTABLE.get().expect("RoutingTable not registered")
+}
+
+pub fn set_tcp_metrics(metrics: TcpMetrics) {
+    TCPMETRICS.get_or_init(|| metrics);
+}
+
+/// counters of the client listener
+pub fn tcp_metrics() -> &'static TcpMetrics {
+    TCPMETRICS.get().expect("TcpMetrics not registered")
+}
+
+/// the globals unit tests rely on, with nats on a [`MemoryBus`](orion::nats_client::MemoryBus),
+/// set once for the whole test binary
+#[cfg(test)]
+pub fn init_for_tests() {
+    use orion::{cluster::registry::ServerRegistry, nats_client::MemoryBus};
+
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        set_nats(NatsClient::with_bus(MemoryBus::new()));
+        set_routes(RouteDict::from_routes(["area.player.move", "chat.world.send"]).unwrap());
+        set_request_limits(RequestLimits::new(8));
+        set_routing_table(RoutingTable::new(ServerRegistry::new()).sticky(
+            "area",
+            "area",
+            orion::cluster::routing::ConsistentHash,
+        ));
+        set_client_manager(ClientManager::new());
+        set_channel_manager(ChannelManager::new());
+    });
+}
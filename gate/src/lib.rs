@@ -1,3 +1,7 @@
-mod client;
-mod protocol;
-mod transport;
+pub mod auth;
+pub mod channel;
+pub mod client;
+pub mod global;
+pub mod protocol;
+pub mod service;
+pub mod transport;
@@ -1,18 +1,37 @@
-use std::env;
+use std::{
+    env::{self, VarError},
+    process,
+    str::FromStr,
+    time::Duration,
+};
 
 use gate::{
-    client::{socket_client::Client, ClientManager},
-    global, transport,
+    auth::{HmacAuthenticator, JwtAuthenticator, TrustAuthenticator},
+    channel::ChannelManager,
+    client::{limit::RequestLimits, socket_client::Client, ClientManager},
+    global,
+    protocol::{
+        compression::{Compression, CompressionConfig},
+        crypto::{EncryptionMode, ServerKey},
+    },
+    service, transport,
+};
+use orion::{
+    app, async_redis,
+    cluster::{
+        registry::{self, ServerInfo, ServerRegistry},
+        routing::{ConsistentHash, RoutingTable},
+    },
+    codec::CodecKind,
+    nats_client::{self, ConnectOptions, Credentials},
+    route::RouteDict,
+    TcpMetrics,
 };
-use orion::{app, async_redis};
+use tracing::{error, info, warn};
 
 #[orion::init_tracing]
 #[tokio::main]
 async fn main() {
-    // let s = fs::read_to_string("gate/config/proto.txt").unwrap();
-    // s.split("\n").for_each(|line| {
-    //     println!("{}", line);
-    // });
     // let mut redis = async_redis::connect("redis://localhost:6379").await;
     // let _: () = redis.set("test", "test_data").await.unwrap();
     // let rv: String = redis.get("test").await.unwrap();
@@ -20,19 +39,158 @@ async fn main() {
     // let r: i32 = redis.del("test").await.unwrap();
     // println!("del: {}", r);
     let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_string());
-    let nats = orion::nats_client::connect(nats_url).await;
+    let nats_options = ConnectOptions {
+        name: Some(format!("{}-{}", app().server_type(), app().uuid())),
+        credentials: env::var("NATS_CREDS")
+            .ok()
+            .map(|path| Credentials::File(path.into())),
+        retry_on_initial_connect: true,
+        ..Default::default()
+    };
+    let nats = match nats_client::connect_with(nats_url, nats_options).await {
+        Ok(nats) => nats,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
     let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
     let redis = async_redis::connect(redis_url).await;
+    let registry = ServerRegistry::new();
+    if let Err(e) = registry.watch(&nats).await {
+        error!("Failed to watch the cluster: {}", e);
+        process::exit(1);
+    }
+    // area servers hold player state, keep each player on the same one
+    global::set_routing_table(RoutingTable::new(registry).sticky("area", "area", ConsistentHash));
     global::set_nats(nats);
     global::set_redis(redis);
     let clientmgr: ClientManager<Client> = ClientManager::new();
     global::set_client_manager(clientmgr);
+    global::set_channel_manager(ChannelManager::new());
+    let routes_path =
+        env::var("ROUTES_PATH").unwrap_or_else(|_| "gate/config/proto.txt".to_string());
+    let routes = RouteDict::from_file(&routes_path).unwrap_or_else(|e| {
+        error!("Bad ROUTES_PATH {}: {}", routes_path, e);
+        process::exit(1);
+    });
+    let limits_path =
+        env::var("LIMITS_PATH").unwrap_or_else(|_| "gate/config/limits.txt".to_string());
+    let max_in_flight = parse_env("MAX_IN_FLIGHT").unwrap_or(16);
+    let mut limits =
+        RequestLimits::from_file(&limits_path, &routes, max_in_flight).unwrap_or_else(|e| {
+            error!("Bad LIMITS_PATH {}: {}", limits_path, e);
+            process::exit(1);
+        });
+    if let Some(timeout) = parse_env("REQUEST_TIMEOUT_MS") {
+        limits = limits.timeout(Duration::from_millis(timeout));
+    }
+    global::set_routes(routes);
+    global::set_request_limits(limits);
+    let mut compression = CompressionConfig::default();
+    if let Ok(codecs) = env::var("COMPRESSION") {
+        compression.codecs = codecs
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Compression::from_name(name.trim()).unwrap_or_else(|| bad_env("COMPRESSION", name))
+            })
+            .collect();
+    }
+    if let Some(threshold) = parse_env("COMPRESSION_THRESHOLD") {
+        compression.threshold = threshold;
+    }
+    global::set_compression(compression);
+    let encryption = match env::var("ENCRYPTION") {
+        Ok(mode) => {
+            EncryptionMode::from_name(&mode).unwrap_or_else(|| bad_env("ENCRYPTION", &mode))
+        }
+        Err(_) => EncryptionMode::default(),
+    };
+    global::set_encryption(encryption);
+    let server_key = match env::var("SERVER_KEY") {
+        Ok(secret) => match ServerKey::from_base64(&secret) {
+            Ok(key) => {
+                info!("Clients should pin server key {}", key.public_key());
+                Some(key)
+            }
+            Err(e) => {
+                error!("Bad SERVER_KEY: {}", e);
+                process::exit(1);
+            }
+        },
+        Err(_) => {
+            if encryption != EncryptionMode::Off {
+                warn!("SERVER_KEY not set, encryption only stops passive eavesdroppers");
+            }
+            None
+        }
+    };
+    global::set_server_key(server_key);
+    let codecs = match env::var("CODECS") {
+        Ok(names) => names
+            .split(',')
+            .map(|name| {
+                CodecKind::from_name(name.trim()).unwrap_or_else(|| bad_env("CODECS", name))
+            })
+            .collect(),
+        Err(_) => CodecKind::ALL.to_vec(),
+    };
+    global::set_codecs(codecs);
+    // an empty key lets anyone sign a token for any uid
+    let auth_secret = |mode: &str| match env::var("AUTH_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret,
+        _ => {
+            error!("AUTH_MODE {} needs AUTH_SECRET", mode);
+            process::exit(1);
+        }
+    };
+    match env::var("AUTH_MODE").as_deref() {
+        Ok("hmac") => global::set_authenticator(HmacAuthenticator::new(auth_secret("hmac"))),
+        Ok("jwt") => {
+            global::set_authenticator(JwtAuthenticator::hs256(auth_secret("jwt").as_bytes()))
+        }
+        Err(VarError::NotPresent) => {
+            warn!("AUTH_MODE not set, trusting client tokens as uids");
+            global::set_authenticator(TrustAuthenticator);
+        }
+        Ok(mode) => bad_env("AUTH_MODE", mode),
+        Err(e) => {
+            error!("Bad AUTH_MODE: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = service::start().await {
+        error!("Failed to start gate services: {}", e);
+        process::exit(1);
+    }
+    // only join once the services are listening
+    let info = ServerInfo::new(app().uuid(), app().server_type());
+    let announcement = match registry::announce(global::nats(), info).await {
+        Ok(announcement) => announcement,
+        Err(e) => {
+            error!("Failed to join the cluster: {}", e);
+            process::exit(1);
+        }
+    };
 
     let addr = env::var("ADDR").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port: u32 = env::var("PORT")
-        .unwrap_or_else(|_| "9001".to_string())
-        .parse()
-        .unwrap();
-    transport::tcp_transport::start(addr, port);
+    let port: u32 = parse_env("PORT").unwrap_or(9001);
+    let tcp_metrics = TcpMetrics::default();
+    global::set_tcp_metrics(tcp_metrics.clone());
+    transport::tcp_transport::start(addr, port, tcp_metrics);
     app().start().await;
+    announcement.leave().await;
+}
+
+/// `None` when `var` is not set, exits when it is set to something that does not parse
+fn parse_env<T: FromStr>(var: &str) -> Option<T> {
+    let value = env::var(var).ok()?;
+    Some(value.parse().unwrap_or_else(|_| bad_env(var, &value)))
+}
+
+fn bad_env(var: &str, value: &str) -> ! {
+    error!("Bad {}: {:?}", var, value);
+    process::exit(1);
 }
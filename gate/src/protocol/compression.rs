@@ -0,0 +1,211 @@
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+/// the largest body the 3 byte packet length can describe
+pub const MAX_DECOMPRESSED_LEN: usize = (1 << 24) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    Zstd,
+    Deflate,
+    Lz4,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::Zstd, Compression::Lz4, Compression::Deflate];
+
+    /// the name used in the handshake
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Deflate => "deflate",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    pub fn compress(self, data: &[u8]) -> Bytes {
+        let compressed = match self {
+            Compression::Zstd => {
+                zstd::bulk::compress(data, 0).expect("zstd compression into a vec")
+            }
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder
+                    .write_all(data)
+                    .and_then(|_| encoder.finish())
+                    .expect("deflate compression into a vec")
+            }
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        };
+        Bytes::from(compressed)
+    }
+
+    /// fails on corrupt input or when the output would exceed [`MAX_DECOMPRESSED_LEN`]
+    pub fn decompress(self, data: &[u8]) -> io::Result<Bytes> {
+        let decompressed = match self {
+            Compression::Zstd => read_limited(zstd::stream::read::Decoder::new(data)?)?,
+            Compression::Deflate => read_limited(DeflateDecoder::new(data))?,
+            Compression::Lz4 => {
+                // the size prefix is checked before lz4_flex allocates it
+                let size = data
+                    .get(..4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing size"))?;
+                if size > MAX_DECOMPRESSED_LEN {
+                    return Err(too_large());
+                }
+                lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+        };
+        Ok(Bytes::from(decompressed))
+    }
+}
+
+/// grows the output with the data instead of allocating the limit up front
+fn read_limited(decoder: impl Read) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder
+        .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+        .read_to_end(&mut out)?;
+    if out.len() > MAX_DECOMPRESSED_LEN {
+        return Err(too_large());
+    }
+    Ok(out)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "decompressed payload too large")
+}
+
+/// codecs the gate accepts and the payload size from which messages are compressed
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub codecs: Vec<Compression>,
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            codecs: Compression::ALL.to_vec(),
+            threshold: 1024,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// the first codec offered by the client, in its order of preference, that the gate
+    /// accepts
+    pub fn negotiate(&self, offered: &[String]) -> Option<MessageCompression> {
+        offered
+            .iter()
+            .filter_map(|name| Compression::from_name(name))
+            .find(|codec| self.codecs.contains(codec))
+            .map(|codec| MessageCompression {
+                codec,
+                threshold: self.threshold,
+            })
+    }
+}
+
+/// the compression agreed with one client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MessageCompression {
+    pub codec: Compression,
+    pub threshold: usize,
+}
+
+impl MessageCompression {
+    /// `None` when the payload is below the threshold or does not get smaller
+    pub fn compress(&self, data: &[u8]) -> Option<Bytes> {
+        if data.len() < self.threshold {
+            return None;
+        }
+        let compressed = self.codec.compress(data);
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Vec<u8> {
+        (0..4096u32).flat_map(|i| (i % 17).to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = snapshot();
+        for codec in Compression::ALL {
+            let compressed = codec.compress(&data);
+            assert!(
+                compressed.len() < data.len(),
+                "{} did not compress",
+                codec.name()
+            );
+            assert_eq!(&codec.decompress(&compressed).unwrap()[..], &data[..]);
+
+            let empty = codec.compress(&[]);
+            assert!(codec.decompress(&empty).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_decompress_corrupt() {
+        for codec in Compression::ALL {
+            assert!(codec.decompress(b"\xff\xff\xff\xff garbage").is_err());
+        }
+    }
+
+    #[test]
+    fn test_decompress_too_large() {
+        let bomb = vec![0; MAX_DECOMPRESSED_LEN + 1];
+        for codec in Compression::ALL {
+            assert!(codec.decompress(&codec.compress(&bomb)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        for codec in Compression::ALL {
+            assert_eq!(Compression::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Compression::from_name("brotli"), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let config = CompressionConfig {
+            codecs: vec![Compression::Lz4, Compression::Deflate],
+            threshold: 64,
+        };
+        let offered = ["brotli", "zstd", "deflate", "lz4"].map(String::from);
+        assert_eq!(
+            config.negotiate(&offered),
+            Some(MessageCompression {
+                codec: Compression::Deflate,
+                threshold: 64
+            })
+        );
+        assert_eq!(config.negotiate(&["zstd".to_string()]), None);
+        assert_eq!(config.negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let compression = MessageCompression {
+            codec: Compression::Zstd,
+            threshold: 1024,
+        };
+        assert!(compression.compress(&[0; 100]).is_none());
+        assert!(compression.compress(&[0; 2048]).is_some());
+    }
+}
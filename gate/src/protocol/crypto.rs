@@ -0,0 +1,450 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_LEN: usize = 32;
+const COUNTER_LEN: usize = 8;
+/// how far behind the newest packet an older one may still arrive
+const REPLAY_WINDOW: u64 = 64;
+const KDF_INFO: &[u8] = b"orion gate packet keys v1";
+
+/// whether the gate accepts or insists on encrypted connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncryptionMode {
+    Off,
+    /// encrypted when the client sends a public key in its handshake
+    #[default]
+    Optional,
+    Required,
+}
+
+impl EncryptionMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(EncryptionMode::Off),
+            "optional" => Some(EncryptionMode::Optional),
+            "required" => Some(EncryptionMode::Required),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    BadKey,
+    Truncated,
+    Decrypt,
+    Replayed,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::BadKey => write!(f, "invalid public key"),
+            CryptoError::Truncated => write!(f, "encrypted body too short"),
+            CryptoError::Decrypt => write!(f, "failed to decrypt body"),
+            CryptoError::Replayed => write!(f, "replayed packet"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// One side of an x25519 key exchange. The public keys travel base64url encoded in the
+/// Handshake packets.
+///
+/// On its own the exchange only keeps out passive eavesdroppers: nothing ties the gate's
+/// key to the gate, so an active man in the middle can run one exchange with each side and
+/// read everything. With a [`ServerKey`] whose public half clients pin, the keys also
+/// depend on the gate's static secret and a man in the middle cannot derive them.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        // used for one connection only, static just so it can take part in two exchanges
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyExchange { secret, public }
+    }
+
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.public.as_bytes())
+    }
+
+    /// unauthenticated, see [`KeyExchange`]
+    pub fn finish(self, peer_public_key: &str, role: Role) -> Result<PacketCipher, CryptoError> {
+        let peer = decode_key(peer_public_key)?;
+        self.derive(peer, role, None)
+    }
+
+    /// the gate's side of an exchange authenticated by its static key
+    pub fn finish_server(
+        self,
+        client_public_key: &str,
+        server_key: &ServerKey,
+    ) -> Result<PacketCipher, CryptoError> {
+        let peer = decode_key(client_public_key)?;
+        let shared = contributory(server_key.secret.diffie_hellman(&peer))?;
+        self.derive(peer, Role::Server, Some((server_key.public, shared)))
+    }
+
+    /// the client's side of an exchange authenticated by `server_key`, the static public
+    /// key of the gate the client has pinned
+    pub fn finish_client(
+        self,
+        server_public_key: &str,
+        server_key: &str,
+    ) -> Result<PacketCipher, CryptoError> {
+        let peer = decode_key(server_public_key)?;
+        let server_key = decode_key(server_key)?;
+        let shared = contributory(self.secret.diffie_hellman(&server_key))?;
+        self.derive(peer, Role::Client, Some((server_key, shared)))
+    }
+
+    /// Each direction gets its own key, derived with HKDF-SHA256 from the shared secrets and
+    /// all public keys, so the two sides never seal with the same key and nonce.
+    fn derive(
+        self,
+        peer: PublicKey,
+        role: Role,
+        server_static: Option<(PublicKey, [u8; KEY_LEN])>,
+    ) -> Result<PacketCipher, CryptoError> {
+        let shared = contributory(self.secret.diffie_hellman(&peer))?;
+        let (client, server) = match role {
+            Role::Client => (self.public, peer),
+            Role::Server => (peer, self.public),
+        };
+        let mut salt = [client.as_bytes().as_slice(), server.as_bytes()].concat();
+        let mut ikm = shared.to_vec();
+        if let Some((public, shared)) = server_static {
+            salt.extend_from_slice(public.as_bytes());
+            ikm.extend_from_slice(&shared);
+        }
+        let mut okm = [0u8; KEY_LEN * 2];
+        Hkdf::<Sha256>::new(Some(&salt), &ikm)
+            .expand(KDF_INFO, &mut okm)
+            .expect("64 bytes is a valid hkdf output length");
+        let (to_server, to_client) = okm.split_at(KEY_LEN);
+        let (seal_key, open_key) = match role {
+            Role::Client => (to_server, to_client),
+            Role::Server => (to_client, to_server),
+        };
+        Ok(PacketCipher {
+            seal_key: ChaCha20Poly1305::new(Key::from_slice(seal_key)),
+            open_key: ChaCha20Poly1305::new(Key::from_slice(open_key)),
+            send_counter: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::default()),
+        })
+    }
+}
+
+/// The long-lived x25519 key of a gate. Clients pin its public half and the gate sends it
+/// in the handshake, so a client can tell whether it reached the gate it trusts.
+pub struct ServerKey {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl fmt::Debug for ServerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerKey")
+            .field("public", &self.public_key())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerKey {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// any 32 random bytes, base64url encoded
+    pub fn from_base64(secret: &str) -> Result<Self, CryptoError> {
+        let secret: [u8; KEY_LEN] = URL_SAFE_NO_PAD
+            .decode(secret.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(CryptoError::BadKey)?;
+        Ok(Self::from_secret(StaticSecret::from(secret)))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        ServerKey { secret, public }
+    }
+
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.public.as_bytes())
+    }
+}
+
+fn decode_key(key: &str) -> Result<PublicKey, CryptoError> {
+    let key: [u8; KEY_LEN] = URL_SAFE_NO_PAD
+        .decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(CryptoError::BadKey)?;
+    Ok(PublicKey::from(key))
+}
+
+/// a low order point from the peer would make the secret predictable
+fn contributory(shared: x25519_dalek::SharedSecret) -> Result<[u8; KEY_LEN], CryptoError> {
+    match shared.was_contributory() {
+        true => Ok(shared.to_bytes()),
+        false => Err(CryptoError::BadKey),
+    }
+}
+
+/// Seals and opens packet bodies with ChaCha20-Poly1305.
+///
+/// A sealed body is an 8 byte big endian counter followed by the ciphertext and tag. The
+/// counter is the nonce, a body whose counter was already opened, or is too old to tell, is
+/// rejected.
+pub struct PacketCipher {
+    seal_key: ChaCha20Poly1305,
+    open_key: ChaCha20Poly1305,
+    send_counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketCipher")
+            .field("send_counter", &self.send_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PacketCipher {
+    pub fn seal(&self, plaintext: &[u8]) -> Bytes {
+        let counter = self.send_counter.fetch_add(1, Ordering::SeqCst);
+        let ciphertext = self
+            .seal_key
+            .encrypt(&nonce(counter), plaintext)
+            .expect("chacha20poly1305 encryption into a vec");
+        let mut buf = BytesMut::with_capacity(COUNTER_LEN + ciphertext.len());
+        buf.put_u64(counter);
+        buf.extend_from_slice(&ciphertext);
+        buf.freeze()
+    }
+
+    pub fn open(&self, body: &[u8]) -> Result<Bytes, CryptoError> {
+        if body.len() < COUNTER_LEN {
+            return Err(CryptoError::Truncated);
+        }
+        let (counter, ciphertext) = body.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        if !self.replay.lock().unwrap().is_fresh(counter) {
+            return Err(CryptoError::Replayed);
+        }
+        let plaintext = self
+            .open_key
+            .decrypt(&nonce(counter), ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        // only an authentic packet may move the window
+        if !self.replay.lock().unwrap().accept(counter) {
+            return Err(CryptoError::Replayed);
+        }
+        Ok(Bytes::from(plaintext))
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+/// counters seen within [`REPLAY_WINDOW`] of the newest one
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// newest counter plus one, 0 before any packet
+    next: u64,
+    /// bit `i` is set when counter `next - 1 - i` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - counter;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    fn accept(&mut self, counter: u64) -> bool {
+        if !self.is_fresh(counter) {
+            return false;
+        }
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = counter + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - counter);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (PacketCipher, PacketCipher) {
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let server_key = server.public_key();
+        (
+            client.finish(&server_key, Role::Client).unwrap(),
+            server.finish(&client_key, Role::Server).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (client, server) = pair();
+        let sealed = client.seal(b"attack goblin");
+        assert_ne!(&sealed[COUNTER_LEN..], b"attack goblin");
+        assert_eq!(&server.open(&sealed).unwrap()[..], b"attack goblin");
+        let sealed = server.seal(b"");
+        assert!(client.open(&sealed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_directions_use_different_keys() {
+        let (client, _server) = pair();
+        // a packet reflected back to its sender does not open
+        let sealed = client.seal(b"hello");
+        assert_eq!(client.open(&sealed), Err(CryptoError::Decrypt));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (client, server) = pair();
+        let mut sealed = client.seal(b"hello").to_vec();
+        *sealed.last_mut().unwrap() ^= 1;
+        assert_eq!(server.open(&sealed), Err(CryptoError::Decrypt));
+        assert_eq!(server.open(&sealed[..4]), Err(CryptoError::Truncated));
+        // a forged counter does not move the window
+        let mut forged = client.seal(b"hello").to_vec();
+        forged[..COUNTER_LEN].copy_from_slice(&1000u64.to_be_bytes());
+        assert_eq!(server.open(&forged), Err(CryptoError::Decrypt));
+        assert!(server.replay.lock().unwrap().next < 1000);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (client, server) = pair();
+        let first = client.seal(b"1");
+        let second = client.seal(b"2");
+        assert!(server.open(&second).is_ok());
+        // out of order within the window is fine, once
+        assert!(server.open(&first).is_ok());
+        assert_eq!(server.open(&first), Err(CryptoError::Replayed));
+        assert_eq!(server.open(&second), Err(CryptoError::Replayed));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(0));
+        assert!(window.accept(100));
+        assert!(!window.accept(0));
+        assert!(window.accept(50));
+        assert!(!window.accept(50));
+        assert!(!window.accept(100 - REPLAY_WINDOW));
+        assert!(window.accept(101 - REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn test_server_key() {
+        let server_key = ServerKey::generate();
+        let pinned = server_key.public_key();
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let client_key = client.public_key();
+        let server_eph = server.public_key();
+        let client = client.finish_client(&server_eph, &pinned).unwrap();
+        let server = server.finish_server(&client_key, &server_key).unwrap();
+        assert_eq!(&server.open(&client.seal(b"hello")).unwrap()[..], b"hello");
+        assert_eq!(&client.open(&server.seal(b"hi")).unwrap()[..], b"hi");
+
+        let again = ServerKey::from_base64(&URL_SAFE_NO_PAD.encode(server_key.secret.to_bytes()));
+        assert_eq!(again.unwrap().public_key(), pinned);
+        assert_eq!(
+            ServerKey::from_base64("short").unwrap_err(),
+            CryptoError::BadKey
+        );
+    }
+
+    #[test]
+    fn test_man_in_the_middle() {
+        let pinned = ServerKey::generate().public_key();
+        // without the gate's secret the attacker can only mix in a key of its own, or none
+        for server_key in [None, Some(ServerKey::generate())] {
+            let client = KeyExchange::new();
+            let attacker = KeyExchange::new();
+            let client_key = client.public_key();
+            let client = client
+                .finish_client(&attacker.public_key(), &pinned)
+                .unwrap();
+            let attacker = match &server_key {
+                Some(key) => attacker.finish_server(&client_key, key),
+                None => attacker.finish(&client_key, Role::Server),
+            }
+            .unwrap();
+            assert_eq!(
+                attacker.open(&client.seal(b"hello")),
+                Err(CryptoError::Decrypt)
+            );
+        }
+    }
+
+    #[test]
+    fn test_bad_key() {
+        assert!(matches!(
+            KeyExchange::new().finish("short", Role::Server),
+            Err(CryptoError::BadKey)
+        ));
+        let zero = URL_SAFE_NO_PAD.encode([0u8; KEY_LEN]);
+        assert!(matches!(
+            KeyExchange::new().finish(&zero, Role::Server),
+            Err(CryptoError::BadKey)
+        ));
+    }
+}
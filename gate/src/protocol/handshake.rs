@@ -0,0 +1,163 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// version of the packet/message protocol spoken by this gate
+/// version 2 widens request ids from one byte to a varint
+pub const PROTOCOL_VERSION: u16 = 2;
+/// oldest client protocol version still accepted
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const CODE_OK: u16 = 0;
+pub const CODE_BAD_REQUEST: u16 = 1;
+pub const CODE_INCOMPATIBLE_VERSION: u16 = 2;
+pub const CODE_AUTH_FAILED: u16 = 3;
+pub const CODE_ENCRYPTION_REQUIRED: u16 = 4;
+pub const CODE_UNSUPPORTED_CODEC: u16 = 5;
+
+/// body of the Handshake packet sent by the client, json encoded
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    pub protocol_version: u16,
+    #[serde(default)]
+    pub client_version: String,
+    #[serde(default)]
+    pub platform: String,
+    #[serde(default)]
+    pub token: String,
+    /// version of the route dictionary cached by the client
+    #[serde(default)]
+    pub route_version: Option<u32>,
+    /// codecs the client can decompress, most preferred first
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// x25519 public key, base64url, asks for an encrypted connection
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// payload codec, such as `json` or `protobuf`, the gate's default when omitted
+    #[serde(default)]
+    pub codec: Option<String>,
+}
+
+/// body of the Handshake packet sent back by the gate, json encoded
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    pub code: u16,
+    pub protocol_version: u16,
+    /// seconds
+    pub heartbeat: u8,
+    pub route_version: u32,
+    /// routes in id order, omitted when the client already has this version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routes: Option<Vec<String>>,
+    /// codec chosen for this connection, messages are sent uncompressed when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// payloads of at least this many bytes are compressed
+    #[serde(default)]
+    pub compression_threshold: usize,
+    /// the gate's x25519 public key when the connection is encrypted. From here on Data
+    /// bodies are sealed, and the HandshakeAck body is an empty sealed body proving the
+    /// client derived the same keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// the gate's static x25519 public key when it authenticates the exchange with one, it
+    /// is then part of the keys. A client that pinned a key gives up unless they match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_key: Option<String>,
+    /// payload codec of this connection
+    #[serde(default)]
+    pub codec: String,
+}
+
+impl HandshakeRequest {
+    pub fn decode(body: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(body)
+    }
+
+    pub fn check_version(&self) -> Result<(), u16> {
+        if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version) {
+            Ok(())
+        } else {
+            Err(CODE_INCOMPATIBLE_VERSION)
+        }
+    }
+}
+
+impl HandshakeResponse {
+    pub fn reject(code: u16) -> Self {
+        HandshakeResponse {
+            code,
+            protocol_version: PROTOCOL_VERSION,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> Bytes {
+        Bytes::from(serde_json::to_vec(self).expect("handshake response should serialize"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_request() {
+        let body =
+            br#"{"protocol_version":1,"client_version":"1.2.0","platform":"ios","token":"abc"}"#;
+        let req = HandshakeRequest::decode(body).unwrap();
+        assert_eq!(req.protocol_version, 1);
+        assert_eq!(req.client_version, "1.2.0");
+        assert_eq!(req.platform, "ios");
+        assert_eq!(req.token, "abc");
+        assert_eq!(req.route_version, None);
+        assert!(req.compression.is_empty());
+        assert!(req.public_key.is_none());
+        assert!(req.check_version().is_ok());
+    }
+
+    #[test]
+    fn test_incompatible_version() {
+        let req = HandshakeRequest::decode(br#"{"protocol_version":99}"#).unwrap();
+        assert_eq!(req.check_version(), Err(CODE_INCOMPATIBLE_VERSION));
+        let req = HandshakeRequest::decode(br#"{"protocol_version":0}"#).unwrap();
+        assert_eq!(req.check_version(), Err(CODE_INCOMPATIBLE_VERSION));
+    }
+
+    #[test]
+    fn test_bad_request() {
+        assert!(HandshakeRequest::decode(b"\x04user").is_err());
+        assert!(HandshakeRequest::decode(br#"{"token":"abc"}"#).is_err());
+    }
+
+    #[test]
+    fn test_encode_response() {
+        let res = HandshakeResponse {
+            code: CODE_OK,
+            protocol_version: PROTOCOL_VERSION,
+            heartbeat: 20,
+            route_version: 7,
+            routes: None,
+            compression: Some("zstd".to_string()),
+            compression_threshold: 1024,
+            public_key: None,
+            server_key: None,
+            codec: "json".to_string(),
+        };
+        let json: serde_json::Value = serde_json::from_slice(&res.encode()).unwrap();
+        assert_eq!(json["code"], 0);
+        assert_eq!(json["heartbeat"], 20);
+        assert_eq!(json["route_version"], 7);
+        assert!(json.get("routes").is_none());
+        assert_eq!(json["compression"], "zstd");
+        assert!(json.get("public_key").is_none());
+        assert!(json.get("server_key").is_none());
+        assert_eq!(json["codec"], "json");
+
+        let rejected: HandshakeResponse =
+            serde_json::from_slice(&HandshakeResponse::reject(CODE_INCOMPATIBLE_VERSION).encode())
+                .unwrap();
+        assert_eq!(rejected.code, CODE_INCOMPATIBLE_VERSION);
+        assert_eq!(rejected.protocol_version, PROTOCOL_VERSION);
+    }
+}
@@ -1,4 +1,9 @@
+use std::fmt;
+
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use super::compression::MessageCompression;
 
 pub enum MsgType {
     Request,
@@ -9,10 +14,186 @@ pub enum MsgType {
 
 const MSG_TYPE_LEN: usize = 1;
 const MSG_PROTOCOL_ID_LEN: usize = 2;
+/// a u32 takes at most 5 bytes as a varint
+const MAX_VARINT_LEN: usize = 5;
+
+/// how request and response ids are written, decided by the protocol version of the client
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdFormat {
+    /// protocol version 1, one byte, ids wrap after 255
+    #[default]
+    Byte,
+    /// protocol version 2 and later, LEB128 encoded u32
+    Varint,
+}
+
+impl IdFormat {
+    pub fn for_version(protocol_version: u16) -> Self {
+        if protocol_version >= 2 {
+            IdFormat::Varint
+        } else {
+            IdFormat::Byte
+        }
+    }
+
+    fn len(self, id: u32) -> usize {
+        match self {
+            IdFormat::Byte => 1,
+            IdFormat::Varint => (32 - (id | 1).leading_zeros() as usize).div_ceil(7),
+        }
+    }
+
+    fn put(self, buf: &mut BytesMut, mut id: u32) {
+        match self {
+            IdFormat::Byte => buf.put_u8(id as u8),
+            IdFormat::Varint => {
+                while id >= 0x80 {
+                    buf.put_u8(id as u8 | 0x80);
+                    id >>= 7;
+                }
+                buf.put_u8(id as u8);
+            }
+        }
+    }
+
+    fn get(self, bytes: &mut Bytes) -> Result<u32, DecodeError> {
+        match self {
+            IdFormat::Byte => Ok(get_u8(bytes)? as u32),
+            IdFormat::Varint => {
+                let mut id = 0u32;
+                for i in 0..MAX_VARINT_LEN {
+                    let b = get_u8(bytes)?;
+                    // the last byte holds the top 4 bits of a u32
+                    if i == MAX_VARINT_LEN - 1 && b > 0x0f {
+                        return Err(DecodeError::IdOverflow);
+                    }
+                    id |= ((b & 0x7f) as u32) << (7 * i);
+                    if b & 0x80 == 0 {
+                        return Ok(id);
+                    }
+                }
+                unreachable!("the last byte has no continuation bit")
+            }
+        }
+    }
+}
+
+/// A message from a client that cannot be read, the client is not following the protocol.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// the message ends inside its header
+    Truncated,
+    UnknownType(u8),
+    /// a varint id that does not fit a u32
+    IdOverflow,
+    /// the compressed flag is set but no compression was agreed at handshake
+    UnexpectedCompression,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "message truncated"),
+            DecodeError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            DecodeError::IdOverflow => write!(f, "message id too long"),
+            DecodeError::UnexpectedCompression => {
+                write!(f, "compressed message without negotiated compression")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn get_u8(bytes: &mut Bytes) -> Result<u8, DecodeError> {
+    if bytes.remaining() < 1 {
+        return Err(DecodeError::Truncated);
+    }
+    Ok(bytes.get_u8())
+}
+
+/// set on the type byte of a response whose body is an [`ErrorBody`] instead of the
+/// backend's reply
+pub const ERROR_FLAG: u8 = 0x40;
+/// set on the type byte when the data is compressed with the codec agreed at handshake
+pub const COMPRESSED_FLAG: u8 = 0x80;
+
+pub const ERR_RATE_LIMITED: u16 = 1;
+pub const ERR_TOO_MANY_IN_FLIGHT: u16 = 2;
+/// the client reused the id of a request still waiting for its response
+pub const ERR_DUPLICATE_ID: u16 = 3;
+pub const ERR_TIMEOUT: u16 = 4;
+pub const ERR_BACKEND_FAILED: u16 = 5;
+pub const ERR_UNKNOWN_ROUTE: u16 = 6;
+pub const ERR_NO_SERVER: u16 = 7;
+/// pushed when the server a player was bound to left the cluster and the player was moved
+pub const ERR_SERVER_LOST: u16 = 8;
+/// the data of the request could not be decompressed
+pub const ERR_BAD_COMPRESSION: u16 = 9;
+
+pub fn error_message(code: u16) -> &'static str {
+    match code {
+        ERR_RATE_LIMITED => "rate limited",
+        ERR_TOO_MANY_IN_FLIGHT => "too many requests in flight",
+        ERR_DUPLICATE_ID => "duplicate request id",
+        ERR_TIMEOUT => "request timed out",
+        ERR_BACKEND_FAILED => "backend failed",
+        ERR_UNKNOWN_ROUTE => "unknown route",
+        ERR_NO_SERVER => "no server available",
+        ERR_SERVER_LOST => "server lost",
+        ERR_BAD_COMPRESSION => "bad compressed data",
+        _ => "request failed",
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: u16,
+    pub message: String,
+}
+
+pub fn encode(
+    format: IdFormat,
+    msg_type: MsgType,
+    protocol_id: u16,
+    id: u32,
+    data: Bytes,
+) -> bytes::Bytes {
+    encode_with_flags(0, format, msg_type, protocol_id, id, &data)
+}
 
-pub fn encode(msg_type: MsgType, protocol_id: u16, id: u8, data: Bytes) -> bytes::Bytes {
+/// compresses the data when `compression` is set and the data is over its threshold
+pub fn encode_compressed(
+    format: IdFormat,
+    msg_type: MsgType,
+    protocol_id: u16,
+    id: u32,
+    data: Bytes,
+    compression: Option<&MessageCompression>,
+) -> Bytes {
+    match compression.and_then(|c| c.compress(&data)) {
+        Some(compressed) => encode_with_flags(
+            COMPRESSED_FLAG,
+            format,
+            msg_type,
+            protocol_id,
+            id,
+            &compressed,
+        ),
+        None => encode(format, msg_type, protocol_id, id, data),
+    }
+}
+
+fn encode_with_flags(
+    flags: u8,
+    format: IdFormat,
+    msg_type: MsgType,
+    protocol_id: u16,
+    id: u32,
+    data: &[u8],
+) -> Bytes {
     let id_len = match msg_type {
-        MsgType::Request | MsgType::Response => 1,
+        MsgType::Request | MsgType::Response => format.len(id),
         _ => 0,
     };
     let mut msg_len = id_len + MSG_TYPE_LEN;
@@ -23,43 +204,88 @@ pub fn encode(msg_type: MsgType, protocol_id: u16, id: u8, data: Bytes) -> bytes
     msg_len += proto_len;
     msg_len += data.len();
     let mut buf = BytesMut::with_capacity(msg_len);
-    buf.put_u8(msg_type as u8);
+    buf.put_u8(msg_type as u8 | flags);
     if id_len > 0 {
-        buf.put_u8(id);
+        format.put(&mut buf, id);
     }
     if proto_len > 0 {
         buf.put_u16(protocol_id);
     }
-    buf.extend_from_slice(&data);
+    buf.extend_from_slice(data);
     buf.freeze()
 }
 
-pub fn decode(mut bytes: Bytes) -> (MsgType, u16, u8, Bytes) {
-    let msg_type = bytes.get_u8();
-    let id = match get_msg_type(msg_type) {
-        MsgType::Request | MsgType::Response => bytes.get_u8(),
+/// a response to request `id` that failed at the gate
+pub fn encode_error(format: IdFormat, id: u32, code: u16, message: &str) -> Bytes {
+    let body = ErrorBody {
+        code,
+        message: message.to_string(),
+    };
+    let data = serde_json::to_vec(&body).expect("error body is always serializable");
+    encode_with_flags(ERROR_FLAG, format, MsgType::Response, 0, id, &data)
+}
+
+/// an error the gate pushes about `protocol_id` outside of any request
+pub fn encode_error_push(protocol_id: u16, code: u16, message: &str) -> Bytes {
+    let body = ErrorBody {
+        code,
+        message: message.to_string(),
+    };
+    let data = serde_json::to_vec(&body).expect("error body is always serializable");
+    // pushes carry no id, any format will do
+    encode_with_flags(
+        ERROR_FLAG,
+        IdFormat::default(),
+        MsgType::Push,
+        protocol_id,
+        0,
+        &data,
+    )
+}
+
+pub fn is_error(msg: &[u8]) -> bool {
+    msg.first().is_some_and(|t| t & ERROR_FLAG != 0)
+}
+
+pub fn is_compressed(msg: &[u8]) -> bool {
+    msg.first().is_some_and(|t| t & COMPRESSED_FLAG != 0)
+}
+
+pub fn decode(
+    format: IdFormat,
+    mut bytes: Bytes,
+) -> Result<(MsgType, u16, u32, Bytes), DecodeError> {
+    let msg_type = get_msg_type(get_u8(&mut bytes)? & !(ERROR_FLAG | COMPRESSED_FLAG))?;
+    let id = match msg_type {
+        MsgType::Request | MsgType::Response => format.get(&mut bytes)?,
         _ => 0,
     };
-    let protocol_id = match get_msg_type(msg_type) {
-        MsgType::Request | MsgType::Notify | MsgType::Push => bytes.get_u16(),
+    let protocol_id = match msg_type {
+        MsgType::Request | MsgType::Notify | MsgType::Push => {
+            if bytes.remaining() < MSG_PROTOCOL_ID_LEN {
+                return Err(DecodeError::Truncated);
+            }
+            bytes.get_u16()
+        }
         _ => 0,
     };
 
-    (get_msg_type(msg_type), protocol_id, id, bytes)
+    Ok((msg_type, protocol_id, id, bytes))
 }
 
-fn get_msg_type(msg_type: u8) -> MsgType {
+fn get_msg_type(msg_type: u8) -> Result<MsgType, DecodeError> {
     match msg_type {
-        0 => MsgType::Request,
-        1 => MsgType::Response,
-        2 => MsgType::Notify,
-        3 => MsgType::Push,
-        _ => panic!("Invalid message type"),
+        0 => Ok(MsgType::Request),
+        1 => Ok(MsgType::Response),
+        2 => Ok(MsgType::Notify),
+        3 => Ok(MsgType::Push),
+        _ => Err(DecodeError::UnknownType(msg_type)),
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::compression::Compression;
 
     #[test]
     fn test_encode_request() {
@@ -68,12 +294,12 @@ mod tests {
         let id = 5;
         let data = Bytes::from("Hello, world!");
 
-        let encoded = encode(msg_type, protocol_id, id, data.clone());
+        let encoded = encode(IdFormat::Byte, msg_type, protocol_id, id, data.clone());
 
         let expected_len = 1 + 1 + 2 + data.len();
         assert_eq!(encoded.len(), expected_len);
 
-        let decoded = decode(encoded);
+        let decoded = decode(IdFormat::Byte, encoded).unwrap();
         assert_eq!(decoded.0 as u8, MsgType::Request as u8);
         assert_eq!(decoded.1, protocol_id);
         assert_eq!(decoded.2, id);
@@ -87,12 +313,12 @@ mod tests {
         let id = 9;
         let data = Bytes::from("Hello, GitHub Copilot!");
 
-        let encoded = encode(msg_type, protocol_id, id, data.clone());
+        let encoded = encode(IdFormat::Byte, msg_type, protocol_id, id, data.clone());
 
         let expected_len = 1 + 1 + data.len();
         assert_eq!(encoded.len(), expected_len);
 
-        let decoded = decode(encoded);
+        let decoded = decode(IdFormat::Byte, encoded).unwrap();
         assert_eq!(decoded.0 as u8, MsgType::Response as u8);
         assert_eq!(decoded.1, 0);
         assert_eq!(decoded.2, id);
@@ -106,12 +332,12 @@ mod tests {
         let id = 0;
         let data = Bytes::from("Hello from the server!");
 
-        let encoded = encode(msg_type, protocol_id, id, data.clone());
+        let encoded = encode(IdFormat::Byte, msg_type, protocol_id, id, data.clone());
 
         let expected_len = 1 + 2 + data.len();
         assert_eq!(encoded.len(), expected_len);
 
-        let decoded = decode(encoded);
+        let decoded = decode(IdFormat::Byte, encoded).unwrap();
         assert_eq!(decoded.0 as u8, MsgType::Notify as u8);
         assert_eq!(decoded.1, protocol_id);
         assert_eq!(decoded.2, id);
@@ -125,15 +351,152 @@ mod tests {
         let id = 0;
         let data = Bytes::from("Pushing updates...");
 
-        let encoded = encode(msg_type, protocol_id, id, data.clone());
+        let encoded = encode(IdFormat::Byte, msg_type, protocol_id, id, data.clone());
 
         let expected_len = 1 + 2 + data.len();
         assert_eq!(encoded.len(), expected_len);
 
-        let decoded = decode(encoded);
+        let decoded = decode(IdFormat::Byte, encoded).unwrap();
         assert_eq!(decoded.0 as u8, MsgType::Push as u8);
         assert_eq!(decoded.1, protocol_id);
         assert_eq!(decoded.2, id);
         assert_eq!(decoded.3, data);
     }
+
+    #[test]
+    fn test_encode_error() {
+        let encoded = encode_error(IdFormat::Byte, 7, ERR_RATE_LIMITED, "rate limited");
+        assert!(is_error(&encoded));
+        assert!(!is_error(&encode(
+            IdFormat::Byte,
+            MsgType::Response,
+            0,
+            7,
+            Bytes::new()
+        )));
+
+        let decoded = decode(IdFormat::Byte, encoded).unwrap();
+        assert_eq!(decoded.0 as u8, MsgType::Response as u8);
+        assert_eq!(decoded.2, 7);
+        let body: ErrorBody = serde_json::from_slice(&decoded.3).unwrap();
+        assert_eq!(
+            body,
+            ErrorBody {
+                code: ERR_RATE_LIMITED,
+                message: "rate limited".to_string(),
+            }
+        );
+
+        let pushed = encode_error_push(2, ERR_SERVER_LOST, "server lost");
+        assert!(is_error(&pushed));
+        let decoded = decode(IdFormat::Byte, pushed).unwrap();
+        assert_eq!(decoded.0 as u8, MsgType::Push as u8);
+        assert_eq!(decoded.1, 2);
+        let body: ErrorBody = serde_json::from_slice(&decoded.3).unwrap();
+        assert_eq!(body.code, ERR_SERVER_LOST);
+    }
+
+    #[test]
+    fn test_encode_compressed() {
+        let data = Bytes::from(vec![7u8; 4096]);
+        for codec in Compression::ALL {
+            let compression = MessageCompression {
+                codec,
+                threshold: 1024,
+            };
+            let encoded = encode_compressed(
+                IdFormat::Byte,
+                MsgType::Push,
+                42,
+                0,
+                data.clone(),
+                Some(&compression),
+            );
+            assert!(is_compressed(&encoded));
+            assert!(encoded.len() < data.len());
+            let (msg_type, protocol_id, _, body) = decode(IdFormat::Byte, encoded).unwrap();
+            assert_eq!(msg_type as u8, MsgType::Push as u8);
+            assert_eq!(protocol_id, 42);
+            assert_eq!(codec.decompress(&body).unwrap(), data);
+
+            // small payloads stay as they are
+            let small = Bytes::from_static(b"hi");
+            let encoded = encode_compressed(
+                IdFormat::Byte,
+                MsgType::Response,
+                0,
+                3,
+                small.clone(),
+                Some(&compression),
+            );
+            assert!(!is_compressed(&encoded));
+            assert_eq!(decode(IdFormat::Byte, encoded).unwrap().3, small);
+        }
+        let encoded = encode_compressed(IdFormat::Byte, MsgType::Push, 42, 0, data.clone(), None);
+        assert!(!is_compressed(&encoded));
+    }
+
+    #[test]
+    fn test_varint_ids() {
+        for id in [
+            0,
+            1,
+            127,
+            128,
+            255,
+            300,
+            16_383,
+            16_384,
+            u16::MAX as u32,
+            u32::MAX,
+        ] {
+            let encoded = encode(IdFormat::Varint, MsgType::Request, 9, id, Bytes::from("x"));
+            assert_eq!(encoded.len(), 1 + IdFormat::Varint.len(id) + 2 + 1);
+            let (_, protocol_id, decoded_id, data) = decode(IdFormat::Varint, encoded).unwrap();
+            assert_eq!(protocol_id, 9);
+            assert_eq!(decoded_id, id);
+            assert_eq!(data, Bytes::from("x"));
+        }
+        assert_eq!(IdFormat::Varint.len(127), 1);
+        assert_eq!(IdFormat::Varint.len(128), 2);
+        assert_eq!(IdFormat::Varint.len(u32::MAX), 5);
+        assert_eq!(IdFormat::for_version(1), IdFormat::Byte);
+        assert_eq!(IdFormat::for_version(2), IdFormat::Varint);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let decode = |format, bytes: &[u8]| decode(format, Bytes::copy_from_slice(bytes)).err();
+        assert_eq!(decode(IdFormat::Byte, b""), Some(DecodeError::Truncated));
+        assert_eq!(
+            decode(IdFormat::Byte, &[4]),
+            Some(DecodeError::UnknownType(4))
+        );
+        assert_eq!(
+            decode(IdFormat::Byte, &[7 | COMPRESSED_FLAG]),
+            Some(DecodeError::UnknownType(7))
+        );
+        // a request without its id, or with only half its protocol id
+        assert_eq!(decode(IdFormat::Byte, &[0]), Some(DecodeError::Truncated));
+        assert_eq!(
+            decode(IdFormat::Byte, &[0, 1, 0]),
+            Some(DecodeError::Truncated)
+        );
+        assert_eq!(decode(IdFormat::Byte, &[2]), Some(DecodeError::Truncated));
+        // a varint that keeps going past the end, and one longer than a u32
+        assert_eq!(
+            decode(IdFormat::Varint, &[0, 0x80, 0x80]),
+            Some(DecodeError::Truncated)
+        );
+        assert_eq!(
+            decode(IdFormat::Varint, &[0, 0xff, 0xff, 0xff, 0xff, 0x1f, 0, 1]),
+            Some(DecodeError::IdOverflow)
+        );
+        assert_eq!(
+            decode(IdFormat::Varint, &[0, 0x80, 0x80, 0x80, 0x80, 0x80, 0, 1]),
+            Some(DecodeError::IdOverflow)
+        );
+        // a response has no protocol id
+        assert_eq!(decode(IdFormat::Byte, &[1, 5]), None);
+    }
 }
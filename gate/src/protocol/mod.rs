@@ -1,2 +1,6 @@
+pub mod compression;
+pub mod crypto;
+pub mod handshake;
 pub mod message;
 pub mod packet;
+pub mod push;
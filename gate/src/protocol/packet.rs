@@ -1,5 +1,10 @@
 use bytes::{BufMut, Bytes, BytesMut};
 
+use super::{
+    crypto::{CryptoError, PacketCipher},
+    message::DecodeError,
+};
+
 const PKT_HEAD_LEN: usize = 4;
 
 pub enum PacketType {
@@ -30,22 +35,41 @@ pub fn encode(pkt_type: PacketType, bytes: Bytes) -> Bytes {
     buf.freeze()
 }
 
-pub fn decode(bytes: Bytes) -> (PacketType, Bytes) {
-    let pkt_type = bytes[0];
+/// seals the body first when the connection is encrypted
+pub fn encode_sealed(pkt_type: PacketType, bytes: Bytes, cipher: Option<&PacketCipher>) -> Bytes {
+    match cipher {
+        Some(cipher) => encode(pkt_type, cipher.seal(&bytes)),
+        None => encode(pkt_type, bytes),
+    }
+}
+
+/// opens a body from [`decode`] when the connection is encrypted
+pub fn open(body: Bytes, cipher: Option<&PacketCipher>) -> Result<Bytes, CryptoError> {
+    match cipher {
+        Some(cipher) => cipher.open(&body),
+        None => Ok(body),
+    }
+}
+
+pub fn decode(bytes: Bytes) -> Result<(PacketType, Bytes), DecodeError> {
+    if bytes.len() < PKT_HEAD_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    let pkt_type = get_pkt_type(bytes[0])?;
     // let length = (bytes[1] as usize) << 16 | (bytes[2] as usize) << 8 | bytes[3] as usize;
     let data = bytes.slice(PKT_HEAD_LEN..);
-    (get_pkt_type(pkt_type), data)
+    Ok((pkt_type, data))
 }
 
-fn get_pkt_type(pkt_type: u8) -> PacketType {
+fn get_pkt_type(pkt_type: u8) -> Result<PacketType, DecodeError> {
     match pkt_type {
-        0 => PacketType::Handshake,
-        1 => PacketType::HandshakeAck,
-        2 => PacketType::Heartbeat,
-        3 => PacketType::Data,
-        4 => PacketType::Kick,
-        5 => PacketType::Error,
-        _ => panic!("Invalid packet type"),
+        0 => Ok(PacketType::Handshake),
+        1 => Ok(PacketType::HandshakeAck),
+        2 => Ok(PacketType::Heartbeat),
+        3 => Ok(PacketType::Data),
+        4 => Ok(PacketType::Kick),
+        5 => Ok(PacketType::Error),
+        _ => Err(DecodeError::UnknownType(pkt_type)),
     }
 }
 
@@ -69,8 +93,38 @@ mod tests {
     fn test_decode() {
         let data = Bytes::from("hello");
         let pkt = encode(PacketType::Data, data);
-        let (pkt_type, data) = decode(pkt);
+        let (pkt_type, data) = decode(pkt).unwrap();
         assert_eq!(pkt_type as u8, PacketType::Data as u8);
         assert_eq!(data, Bytes::from("hello"));
+
+        assert_eq!(
+            decode(Bytes::from_static(&[3, 0])).err(),
+            Some(DecodeError::Truncated)
+        );
+        assert_eq!(
+            decode(Bytes::from_static(&[9, 0, 0, 0])).err(),
+            Some(DecodeError::UnknownType(9))
+        );
+    }
+
+    #[test]
+    fn test_sealed() {
+        use crate::protocol::crypto::{KeyExchange, Role};
+
+        let client = KeyExchange::new();
+        let server = KeyExchange::new();
+        let server_key = server.public_key();
+        let server = server.finish(&client.public_key(), Role::Server).unwrap();
+        let client = client.finish(&server_key, Role::Client).unwrap();
+
+        let pkt = encode_sealed(PacketType::Data, Bytes::from("hello"), Some(&client));
+        let (pkt_type, body) = decode(pkt).unwrap();
+        assert_eq!(pkt_type as u8, PacketType::Data as u8);
+        assert_ne!(body, Bytes::from("hello"));
+        assert_eq!(open(body, Some(&server)).unwrap(), Bytes::from("hello"));
+
+        let pkt = encode_sealed(PacketType::Data, Bytes::from("hello"), None);
+        let (_, body) = decode(pkt).unwrap();
+        assert_eq!(open(body, None).unwrap(), Bytes::from("hello"));
     }
 }
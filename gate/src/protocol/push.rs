@@ -0,0 +1,134 @@
+use std::{collections::HashMap, fmt};
+
+use bytes::Bytes;
+use orion::{broadcast::PushPayload, codec::CodecKind};
+
+use super::{
+    compression::MessageCompression,
+    message::{self, IdFormat, MsgType},
+    packet,
+};
+
+type Key = (CodecKind, Option<MessageCompression>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// the push has no payload in the codec of the client
+    NoPayload(CodecKind),
+    /// the client does not read fast enough, the push was dropped
+    Full,
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::NoPayload(codec) => write!(f, "no payload in {}", codec.name()),
+            PushError::Full => write!(f, "send queue full"),
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+/// A push sent to many clients. The message and its packet are encoded once per codec and
+/// compression setting in use and shared by every client with that setting, encrypted
+/// connections seal the shared message themselves.
+pub struct SharedPush {
+    protocol_id: u16,
+    payload: PushPayload,
+    messages: HashMap<Key, Bytes>,
+    packets: HashMap<Key, Bytes>,
+}
+
+impl SharedPush {
+    pub fn new(protocol_id: u16, payload: PushPayload) -> Self {
+        SharedPush {
+            protocol_id,
+            payload,
+            messages: HashMap::new(),
+            packets: HashMap::new(),
+        }
+    }
+
+    /// `None` if the push has no payload for the codec
+    pub fn message(
+        &mut self,
+        codec: CodecKind,
+        compression: Option<&MessageCompression>,
+    ) -> Option<Bytes> {
+        let key = (codec, compression.copied());
+        if let Some(msg) = self.messages.get(&key) {
+            return Some(msg.clone());
+        }
+        // pushes carry no id, any format will do
+        let msg = message::encode_compressed(
+            IdFormat::default(),
+            MsgType::Push,
+            self.protocol_id,
+            0,
+            self.payload.get(codec)?.clone(),
+            compression,
+        );
+        self.messages.insert(key, msg.clone());
+        Some(msg)
+    }
+
+    pub fn packet(
+        &mut self,
+        codec: CodecKind,
+        compression: Option<&MessageCompression>,
+    ) -> Option<Bytes> {
+        let key = (codec, compression.copied());
+        if let Some(pkt) = self.packets.get(&key) {
+            return Some(pkt.clone());
+        }
+        let pkt = packet::encode(packet::PacketType::Data, self.message(codec, compression)?);
+        self.packets.insert(key, pkt.clone());
+        Some(pkt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::compression::Compression;
+
+    #[test]
+    fn test_shared_packets() {
+        let zstd = MessageCompression {
+            codec: Compression::Zstd,
+            threshold: 16,
+        };
+        let payload = PushPayload::new().with(CodecKind::Json, Bytes::from(vec![1u8; 256]));
+        let mut push = SharedPush::new(3, payload);
+        let raw = push.packet(CodecKind::Json, None).unwrap();
+        let compressed = push.packet(CodecKind::Json, Some(&zstd)).unwrap();
+        assert!(compressed.len() < raw.len());
+        assert_eq!(
+            push.packet(CodecKind::Json, Some(&zstd)).unwrap(),
+            compressed
+        );
+        assert_eq!(push.packets.len(), 2);
+    }
+
+    #[test]
+    fn test_per_codec() {
+        let payload = PushPayload::new()
+            .with(CodecKind::Json, Bytes::from(r#"{"text":"hi"}"#))
+            .with(CodecKind::Protobuf, Bytes::from("\n\x02hi"));
+        let mut push = SharedPush::new(3, payload);
+        for (codec, data) in [
+            (CodecKind::Json, &br#"{"text":"hi"}"#[..]),
+            (CodecKind::Protobuf, &b"\n\x02hi"[..]),
+        ] {
+            let msg = push.message(codec, None).unwrap();
+            let (_, protocol_id, _, decoded) = message::decode(IdFormat::default(), msg).unwrap();
+            assert_eq!(protocol_id, 3);
+            assert_eq!(&decoded[..], data);
+        }
+
+        let mut json_only =
+            SharedPush::new(3, PushPayload::new().with(CodecKind::Json, Bytes::new()));
+        assert!(json_only.packet(CodecKind::Protobuf, None).is_none());
+    }
+}
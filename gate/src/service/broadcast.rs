@@ -0,0 +1,49 @@
+use orion::{
+    broadcast::{BroadcastCommand, PushPayload, BROADCAST_SUBJECT},
+    nats_client::SubscriptionError,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    channel::push_to,
+    client::{socket_client::Client, ClientManager},
+    global,
+    protocol::push::SharedPush,
+};
+
+/// the client list is copied first so the manager is not locked while sending,
+/// returns how many clients it was sent to
+pub fn broadcast(
+    client_mgr: &ClientManager<Client>,
+    protocol_id: u16,
+    payload: PushPayload,
+    ready_only: bool,
+) -> usize {
+    let clients = client_mgr
+        .clients()
+        .into_iter()
+        .filter(|client| !ready_only || client.is_ready());
+    push_to(clients, SharedPush::new(protocol_id, payload), "all")
+}
+
+pub async fn start() -> Result<(), SubscriptionError> {
+    global::nats()
+        .subscribe(BROADCAST_SUBJECT.to_string(), |msg| {
+            let (cmd, payload) = match BroadcastCommand::decode(msg.payload) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    error!("Failed to decode broadcast: {}", e);
+                    return;
+                }
+            };
+            let Some(protocol_id) = global::routes().id(&cmd.route) else {
+                warn!("Failed to broadcast: unknown route {}", cmd.route);
+                return;
+            };
+            // pushes never wait for a client, so they go out right here and in order
+            let client_mgr = global::client_manager_copy();
+            let sent = broadcast(&client_mgr, protocol_id, payload, cmd.ready_only);
+            info!("Broadcast {} to {} clients", cmd.route, sent);
+        })
+        .await
+}
@@ -0,0 +1,65 @@
+use orion::{
+    channel::{self, ChannelCommand, CHANNEL_SUBJECT},
+    nats_client::{Concurrency, SubscriptionError},
+};
+use tracing::error;
+
+use crate::global;
+
+/// channels handled at once, commands on one channel always wait for the previous one
+const LANES: usize = 16;
+
+pub async fn start() -> Result<(), SubscriptionError> {
+    global::nats()
+        .subscribe_async(
+            CHANNEL_SUBJECT.to_string(),
+            // commands that touch every channel share one lane
+            Concurrency::ordered(LANES, |msg| {
+                channel::from_headers(msg.headers.as_ref())
+                    .unwrap_or_default()
+                    .to_string()
+            }),
+            |msg| async move {
+                match ChannelCommand::decode(msg.payload) {
+                    Ok((cmd, payload)) => global::channel_manager().execute(cmd, payload),
+                    Err(e) => error!("Failed to decode channel command: {}", e),
+                }
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use orion::channel::ChannelClient;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commands_in_order() {
+        global::init_for_tests();
+        start().await.unwrap();
+        let client = ChannelClient::new(global::nats().clone());
+        let rooms: Vec<_> = (0..50).map(|i| format!("ordered.{}", i)).collect();
+        for room in &rooms {
+            client.join(room, "user1").await;
+            client.join(room, "user2").await;
+            client.leave(room, "user1").await;
+        }
+        let settled = || {
+            rooms
+                .iter()
+                .all(|room| global::channel_manager().members(room) == ["user2"])
+        };
+        for _ in 0..100 {
+            if settled() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("channel commands were not applied in order");
+    }
+}
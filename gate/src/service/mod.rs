@@ -0,0 +1,12 @@
+pub mod broadcast;
+pub mod channel;
+pub mod session;
+
+use orion::nats_client::SubscriptionError;
+
+/// serves the calls backend servers make to this gate
+pub async fn start() -> Result<(), SubscriptionError> {
+    session::start().await?;
+    channel::start().await?;
+    broadcast::start().await
+}
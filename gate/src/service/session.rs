@@ -0,0 +1,58 @@
+use orion::{
+    app,
+    cluster::routing::binding_key,
+    nats_client::SubscriptionError,
+    session::{Session, SessionService, SessionServiceServer, SessionUpdate},
+};
+
+use crate::{
+    client::{socket_client::Client, ClientManager},
+    global,
+};
+
+pub struct GateSessionService {
+    client_mgr: ClientManager<Client>,
+}
+
+impl GateSessionService {
+    pub fn new(client_mgr: ClientManager<Client>) -> Self {
+        GateSessionService { client_mgr }
+    }
+}
+
+impl SessionService for GateSessionService {
+    async fn update(&self, uid: String, update: SessionUpdate) -> bool {
+        match self.client_mgr.get_client_by_uid(&uid) {
+            Some(client) => {
+                client.update_session(|session| session.apply(update));
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn get(&self, uid: String) -> Option<Session> {
+        self.client_mgr
+            .get_client_by_uid(&uid)
+            .map(|client| client.session())
+    }
+
+    async fn bind(&self, uid: String, server_type: String, server_id: u32) -> bool {
+        let registry = global::routing_table().registry();
+        if !registry.contains(&server_type, server_id) {
+            return false;
+        }
+        match self.client_mgr.get_client_by_uid(&uid) {
+            Some(client) => client
+                .update_session(|session| session.set(binding_key(&server_type), server_id))
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+pub async fn start() -> Result<(), SubscriptionError> {
+    SessionServiceServer::new(GateSessionService::new(global::client_manager_copy()))
+        .serve_at(global::nats(), app().uuid())
+        .await
+}
@@ -1,16 +1,45 @@
+use std::time::Duration;
+
 use crate::{client::NetClient, global};
 
 use bytes::Bytes;
-use orion::SocketListener;
+use orion::{CloseReason, RateLimit, RateLimitPolicy, SocketListener, TcpConfig, TcpMetrics};
 
-use crate::client::{socket_client::Client, ClientManager};
+use crate::client::{
+    socket_client::{Client, HEARTBEAT_INTERVAL},
+    ClientManager,
+};
 use tracing::error;
 
-pub fn start(addr: String, port: u32) {
+/// bytes, header included, far above any message a player sends
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// `metrics` counts the connections of the listener
+pub fn start(addr: String, port: u32, metrics: TcpMetrics) {
     tokio::spawn(async move {
         orion::serve_tcp(
             addr,
             port,
+            TcpConfig {
+                idle_timeout: Some(Duration::from_secs(HEARTBEAT_INTERVAL as u64 * 2)),
+                packet_limit: Some(RateLimit {
+                    per_second: 50,
+                    burst: 100,
+                }),
+                // the burst must hold the largest packet, or it gets the player kicked
+                byte_limit: Some(RateLimit {
+                    per_second: 64 * 1024,
+                    burst: MAX_PACKET_SIZE as u32,
+                }),
+                max_packet_size: Some(MAX_PACKET_SIZE),
+                metrics,
+                rate_limit_policy: RateLimitPolicy::Kick,
+                max_connections: Some(10_000),
+                max_connections_per_ip: Some(32),
+                // players could not reach any backend while the bus is down
+                accepting: Some(global::nats().connected()),
+                ..Default::default()
+            },
             TcpEventListener {
                 client_mgr: global::client_manager_copy(),
             },
@@ -43,11 +72,11 @@ impl SocketListener for TcpEventListener {
         }
     }
 
-    async fn onclose(&mut self, socket_handle: orion::SocketHandle) {
+    async fn onclose(&mut self, socket_handle: orion::SocketHandle, reason: CloseReason) {
         let id = socket_handle.id();
         let result = self.client_mgr.get_client(id);
         if let Some(client) = result {
-            client.onclose().await;
+            client.onclose(reason).await;
             self.client_mgr.remove_client(id);
         }
     }
@@ -2,6 +2,8 @@ use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
 use syn::{parse_macro_input, Stmt};
 
+mod rpc;
+
 #[proc_macro_attribute]
 pub fn init_tracing(_: TokenStream, item: TokenStream) -> TokenStream {
     // eprintln!("inititem: {:#?}", item);
@@ -33,6 +35,31 @@ pub fn init_tracing(_: TokenStream, item: TokenStream) -> TokenStream {
     item_fn.to_token_stream().into()
 }
 
+/// Turns a trait of `async fn(&self, ..) -> T` methods into an RPC service.
+///
+/// Besides the trait itself, this generates `<Trait>Client`, which sends each call as a
+/// NATS request, and `<Trait>Server`, which subscribes one subject per method and
+/// dispatches the requests to an implementation of the trait. Arguments and return
+/// values must be serde serializable.
+///
+/// ```ignore
+/// #[orion::rpc]
+/// pub trait Greeter {
+///     async fn hello(&self, name: String) -> String;
+/// }
+///
+/// GreeterServer::new(MyGreeter).serve(&nats).await?;
+/// let reply = GreeterClient::new(nats).hello("world".to_string()).await?;
+/// ```
+#[proc_macro_attribute]
+pub fn rpc(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as rpc::RpcArgs);
+    let item_trait = parse_macro_input!(item as syn::ItemTrait);
+    rpc::expand(args, item_trait)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
 // #[proc_macro_attribute]
 // pub fn asshole(_: TokenStream, item: TokenStream) -> TokenStream {
 //     eprintln!("itemtokens: {:#?}", item);
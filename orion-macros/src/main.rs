@@ -1,5 +1,4 @@
 use orion_macros::init_tracing;
-use tracing::{debug, error, info};
 
 #[init_tracing]
 fn main() {
@@ -0,0 +1,270 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    FnArg, Ident, ItemTrait, LitStr, Pat, ReturnType, Token, TraitItem, TraitItemFn, Type,
+};
+
+/// `#[rpc]` or `#[rpc(name = "greeter")]`
+pub struct RpcArgs {
+    name: Option<LitStr>,
+}
+
+impl Parse for RpcArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Ok(RpcArgs { name: None });
+        }
+        let key: Ident = input.parse()?;
+        if key != "name" {
+            return Err(syn::Error::new(key.span(), "expected `name = \"...\"`"));
+        }
+        input.parse::<Token![=]>()?;
+        let name: LitStr = input.parse()?;
+        Ok(RpcArgs { name: Some(name) })
+    }
+}
+
+struct Method {
+    ident: Ident,
+    arg_names: Vec<Ident>,
+    arg_types: Vec<Type>,
+    output: Type,
+}
+
+pub fn expand(args: RpcArgs, mut item_trait: ItemTrait) -> syn::Result<TokenStream> {
+    let trait_ident = item_trait.ident.clone();
+    let vis = item_trait.vis.clone();
+    let service = args
+        .name
+        .map(|n| n.value())
+        .unwrap_or_else(|| to_snake_case(&trait_ident.to_string()));
+
+    let mut methods = vec![];
+    for item in item_trait.items.iter_mut() {
+        if let TraitItem::Fn(method) = item {
+            methods.push(parse_method(method)?);
+            make_send(method);
+        }
+    }
+    item_trait
+        .supertraits
+        .push(syn::parse_quote!(::std::marker::Send));
+    item_trait
+        .supertraits
+        .push(syn::parse_quote!(::std::marker::Sync));
+    item_trait.supertraits.push(syn::parse_quote!('static));
+
+    let client_ident = format_ident!("{}Client", trait_ident);
+    let server_ident = format_ident!("{}Server", trait_ident);
+
+    let client_methods = methods.iter().map(|m| {
+        let Method {
+            ident,
+            arg_names,
+            arg_types,
+            output,
+        } = m;
+        let method_name = ident.to_string();
+        quote! {
+            pub async fn #ident(&self, #(#arg_names: #arg_types),*) -> ::std::result::Result<#output, ::orion::rpc::RpcError> {
+                let payload = ::orion::rpc::encode(&(#(#arg_names,)*))?;
+                let subject = ::orion::rpc::subject(#service, self.target.as_deref(), #method_name);
+                let reply = self
+                    .nats
+                    .try_request(subject, payload)
+                    .await
+                    .map_err(::orion::rpc::RpcError::Request)?;
+                ::orion::rpc::decode_reply(&reply.payload)
+            }
+        }
+    });
+
+    let dispatch_arms = methods.iter().map(|m| {
+        let Method {
+            ident,
+            arg_names,
+            arg_types,
+            ..
+        } = m;
+        let method_name = ident.to_string();
+        quote! {
+            #method_name => {
+                let (#(#arg_names,)*): (#(#arg_types,)*) = ::orion::rpc::decode(payload)?;
+                ::orion::rpc::encode_reply(&self.service.#ident(#(#arg_names),*).await)
+            }
+        }
+    });
+
+    let method_names: Vec<String> = methods.iter().map(|m| m.ident.to_string()).collect();
+
+    Ok(quote! {
+        #item_trait
+
+        #[doc = concat!("NATS client stub for [`", stringify!(#trait_ident), "`].")]
+        #[derive(Clone, Debug)]
+        #vis struct #client_ident {
+            nats: ::orion::nats_client::NatsClient,
+            target: ::std::option::Option<::std::string::String>,
+        }
+
+        impl #client_ident {
+            pub fn new(nats: ::orion::nats_client::NatsClient) -> Self {
+                Self { nats, target: ::std::option::Option::None }
+            }
+
+            /// sends the calls to the server serving at `target` only
+            pub fn at(mut self, target: impl ::std::string::ToString) -> Self {
+                self.target = ::std::option::Option::Some(target.to_string());
+                self
+            }
+
+            #(#client_methods)*
+        }
+
+        #[doc = concat!("Dispatches NATS requests to an implementation of [`", stringify!(#trait_ident), "`].")]
+        #vis struct #server_ident<S> {
+            service: ::std::sync::Arc<S>,
+        }
+
+        impl<S> ::std::clone::Clone for #server_ident<S> {
+            fn clone(&self) -> Self {
+                Self {
+                    service: self.service.clone(),
+                }
+            }
+        }
+
+        impl<S: #trait_ident> #server_ident<S> {
+            pub const SERVICE: &'static str = #service;
+
+            pub fn new(service: S) -> Self {
+                Self {
+                    service: ::std::sync::Arc::new(service),
+                }
+            }
+
+            /// decodes `payload` as the arguments of `method`, calls it and encodes the reply
+            pub async fn dispatch(
+                &self,
+                method: &str,
+                payload: &[u8],
+            ) -> ::std::result::Result<::orion::rpc::__private::Bytes, ::orion::rpc::RpcError> {
+                match method {
+                    #(#dispatch_arms)*
+                    _ => ::std::result::Result::Err(::orion::rpc::RpcError::UnknownMethod(method.to_string())),
+                }
+            }
+
+            /// subscribes one subject per method, each call goes to one of the servers
+            /// serving the service
+            pub async fn serve(self, nats: &::orion::nats_client::NatsClient) -> ::std::result::Result<(), ::orion::nats_client::SubscriptionError> {
+                self.serve_target(nats, ::std::option::Option::None).await
+            }
+
+            /// only receives the calls of clients created with `.at(target)`
+            pub async fn serve_at(self, nats: &::orion::nats_client::NatsClient, target: impl ::std::string::ToString) -> ::std::result::Result<(), ::orion::nats_client::SubscriptionError> {
+                self.serve_target(nats, ::std::option::Option::Some(&target.to_string())).await
+            }
+
+            async fn serve_target(self, nats: &::orion::nats_client::NatsClient, target: ::std::option::Option<&str>) -> ::std::result::Result<(), ::orion::nats_client::SubscriptionError> {
+                #(
+                    {
+                        let server = self.clone();
+                        ::orion::rpc::serve_method(nats, #service, target, #method_names, move |payload| {
+                            let server = server.clone();
+                            async move { server.dispatch(#method_names, &payload).await }
+                        })
+                        .await?;
+                    }
+                )*
+                ::std::result::Result::Ok(())
+            }
+        }
+    })
+}
+
+fn parse_method(method: &TraitItemFn) -> syn::Result<Method> {
+    let sig = &method.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            sig,
+            "rpc methods must be `async fn`",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "rpc methods cannot be generic",
+        ));
+    }
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => {}
+        _ => {
+            return Err(syn::Error::new_spanned(
+                sig,
+                "rpc methods must take `&self` as the first argument",
+            ))
+        }
+    }
+    let mut arg_names = vec![];
+    let mut arg_types = vec![];
+    for arg in inputs {
+        let FnArg::Typed(pat_type) = arg else {
+            unreachable!("receiver can only be the first argument");
+        };
+        match pat_type.pat.as_ref() {
+            Pat::Ident(pat) => arg_names.push(pat.ident.clone()),
+            other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "rpc arguments must be plain identifiers",
+                ))
+            }
+        }
+        arg_types.push(pat_type.ty.as_ref().clone());
+    }
+    let output = match &sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => ty.as_ref().clone(),
+    };
+    Ok(Method {
+        ident: sig.ident.clone(),
+        arg_names,
+        arg_types,
+        output,
+    })
+}
+
+/// `async fn f(&self) -> T` becomes `fn f(&self) -> impl Future<Output = T> + Send`
+/// so the server can spawn the returned future
+fn make_send(method: &mut TraitItemFn) {
+    let sig = &mut method.sig;
+    sig.asyncness = None;
+    let output: Type = match &sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => ty.as_ref().clone(),
+    };
+    sig.output = syn::parse_quote! {
+        -> impl ::std::future::Future<Output = #output> + ::std::marker::Send
+    };
+    if let Some(block) = method.default.take() {
+        method.default = Some(syn::parse_quote!({ async move #block }));
+    }
+}
+
+fn to_snake_case(name: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
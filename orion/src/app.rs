@@ -11,6 +11,7 @@ use tracing::info;
 
 pub struct Application {
     uuid: u32,
+    server_type: String,
 }
 
 impl Application {
@@ -20,6 +21,7 @@ impl Application {
                 .unwrap_or_else(|_| 0.to_string())
                 .parse()
                 .expect("server_id should be a number"),
+            server_type: env::var("server_type").unwrap_or_else(|_| default_server_type()),
         }
     }
 
@@ -27,6 +29,11 @@ impl Application {
         self.uuid
     }
 
+    /// every instance of a type shares a queue group, so each message reaches one of them
+    pub fn server_type(&self) -> &str {
+        &self.server_type
+    }
+
     pub async fn start(&self) {
         info!("Application has started");
         let mut sigterm = signal(SignalKind::terminate()).unwrap();
@@ -45,8 +52,16 @@ impl Application {
     async fn shutdown(&self) {}
 }
 
+/// the name of the binary, `gate` for the gate
+fn default_server_type() -> String {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_stem()?.to_str().map(str::to_string))
+        .expect("server_type should be set")
+}
+
 // only immutable data can be stored in a static variable
 pub fn app() -> &'static Application {
     static APP: OnceLock<Application> = OnceLock::new();
-    APP.get_or_init(|| Application::new())
+    APP.get_or_init(Application::new)
 }
@@ -21,7 +21,7 @@ pub async fn connect(url: String) -> ConnectionManager {
             match con_result {
                 Ok(con) => {
                     info!("Connected to redis");
-                    return con;
+                    con
                 },
                 Err(e) => panic!("Failed to connect to redis: {}", e),
             }
@@ -0,0 +1,171 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, CodecError, CodecKind},
+    framing,
+    nats_client::NatsClient,
+};
+
+/// every gate subscribes to this subject
+pub const BROADCAST_SUBJECT: &str = "gate.broadcast";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BroadcastCommand {
+    /// the payload is pushed as a message of this route
+    pub route: String,
+    /// skip clients that have not finished the handshake
+    pub ready_only: bool,
+}
+
+impl BroadcastCommand {
+    pub fn encode(&self, payload: &PushPayload) -> Bytes {
+        framing::encode(self, &payload.to_bytes())
+    }
+
+    pub fn decode(bytes: Bytes) -> Result<(Self, PushPayload), serde_json::Error> {
+        let (cmd, payload) = framing::decode(bytes)?;
+        Ok((cmd, PushPayload::from_bytes(payload)?))
+    }
+}
+
+/// The payload of a push in each codec clients may speak, gates send every client the one
+/// of the codec it chose in the handshake.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PushPayload {
+    payloads: Vec<(CodecKind, Bytes)>,
+}
+
+impl PushPayload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// encodes the value in every codec
+    pub fn encode<T>(value: &T) -> Result<Self, CodecError>
+    where
+        CodecKind: Codec<T>,
+    {
+        let mut payload = Self::new();
+        for codec in CodecKind::ALL {
+            payload = payload.with(codec, codec.encode(value)?);
+        }
+        Ok(payload)
+    }
+
+    /// the payload already encoded with `codec`, clients speaking a codec without one are
+    /// skipped
+    pub fn with(mut self, codec: CodecKind, bytes: Bytes) -> Self {
+        self.payloads.retain(|(c, _)| *c != codec);
+        self.payloads.push((codec, bytes));
+        self
+    }
+
+    pub fn get(&self, codec: CodecKind) -> Option<&Bytes> {
+        self.payloads
+            .iter()
+            .find(|(c, _)| *c == codec)
+            .map(|(_, bytes)| bytes)
+    }
+
+    /// format, once per codec:
+    ///
+    /// +-------+--------+-------+
+    /// | codec | length | bytes |
+    /// +-------+--------+-------+
+    /// | 1B    | 4B     | N     |
+    /// +-------+--------+-------+
+    ///
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let len = self.payloads.iter().map(|(_, b)| 5 + b.len()).sum();
+        let mut buf = BytesMut::with_capacity(len);
+        for (codec, bytes) in &self.payloads {
+            buf.put_u8(*codec as u8);
+            buf.put_u32(bytes.len() as u32);
+            buf.extend_from_slice(bytes);
+        }
+        buf.freeze()
+    }
+
+    /// codecs this gate does not know are left out
+    pub(crate) fn from_bytes(mut bytes: Bytes) -> Result<Self, serde_json::Error> {
+        let mut payload = Self::new();
+        while bytes.has_remaining() {
+            if bytes.remaining() < 5 {
+                return Err(serde::de::Error::custom("push payload truncated"));
+            }
+            let id = bytes.get_u8();
+            let len = bytes.get_u32() as usize;
+            if bytes.remaining() < len {
+                return Err(serde::de::Error::custom("push payload truncated"));
+            }
+            let data = bytes.split_to(len);
+            if let Some(codec) = CodecKind::ALL.into_iter().find(|c| *c as u8 == id) {
+                payload = payload.with(codec, data);
+            }
+        }
+        Ok(payload)
+    }
+}
+
+/// Pushes one message to every client of every gate, e.g. a maintenance warning.
+pub async fn broadcast(
+    nats: &NatsClient,
+    route: impl Into<String>,
+    payload: PushPayload,
+    ready_only: bool,
+) {
+    let cmd = BroadcastCommand {
+        route: route.into(),
+        ready_only,
+    };
+    nats.publish(BROADCAST_SUBJECT.to_string(), cmd.encode(&payload))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let cmd = BroadcastCommand {
+            route: "sys.notice".to_string(),
+            ready_only: true,
+        };
+        let payload = PushPayload::new()
+            .with(CodecKind::Json, Bytes::from(r#""maintenance""#))
+            .with(CodecKind::Protobuf, Bytes::from("\n\x0bmaintenance"));
+        let (decoded, decoded_payload) = BroadcastCommand::decode(cmd.encode(&payload)).unwrap();
+        assert_eq!(decoded, cmd);
+        assert_eq!(decoded_payload, payload);
+
+        let (_, empty) = BroadcastCommand::decode(cmd.encode(&PushPayload::new())).unwrap();
+        assert_eq!(empty.get(CodecKind::Json), None);
+    }
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    struct Notice {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[test]
+    fn test_push_payload() {
+        let notice = Notice {
+            text: "maintenance".to_string(),
+        };
+        let payload = PushPayload::encode(&notice).unwrap();
+        for codec in CodecKind::ALL {
+            let decoded: Notice = codec.decode(payload.get(codec).unwrap()).unwrap();
+            assert_eq!(decoded, notice);
+        }
+
+        // a codec added later is skipped, a cut off payload is an error
+        let mut bytes = BytesMut::from(&payload.to_bytes()[..]);
+        bytes.extend_from_slice(&[0xff, 0, 0, 0, 1, 0]);
+        assert_eq!(PushPayload::from_bytes(bytes.freeze()).unwrap(), payload);
+        let bytes = payload.to_bytes();
+        assert!(PushPayload::from_bytes(bytes.slice(..bytes.len() - 1)).is_err());
+    }
+}
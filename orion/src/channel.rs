@@ -0,0 +1,193 @@
+use async_nats::HeaderMap;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{broadcast::PushPayload, framing, nats_client::NatsClient};
+
+/// every gate subscribes to this subject, so a command reaches all of them
+pub const CHANNEL_SUBJECT: &str = "gate.channel";
+
+/// NATS header holding the channel of a command, so gates can order the commands of a
+/// channel without decoding them
+pub const CHANNEL_HEADER: &str = "Orion-Channel";
+
+/// the channel from the headers a command was sent with
+pub fn from_headers(headers: Option<&HeaderMap>) -> Option<&str> {
+    headers?.get(CHANNEL_HEADER).map(|channel| channel.as_str())
+}
+
+/// Channels are named groups of players, such as a room, a guild or the world chat.
+/// Membership is kept by uid on every gate, each gate pushes to the members connected to it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ChannelCommand {
+    Create {
+        channel: String,
+    },
+    Destroy {
+        channel: String,
+    },
+    Join {
+        channel: String,
+        uid: String,
+    },
+    Leave {
+        channel: String,
+        uid: String,
+    },
+    /// the player left every channel, sent by its gate when it disconnects
+    LeaveAll {
+        uid: String,
+    },
+    /// the payload is pushed to the members as a message of `route`
+    Broadcast {
+        channel: String,
+        route: String,
+    },
+}
+
+impl ChannelCommand {
+    /// only broadcasts carry a payload
+    pub fn encode(&self, payload: &PushPayload) -> Bytes {
+        framing::encode(self, &payload.to_bytes())
+    }
+
+    pub fn decode(bytes: Bytes) -> Result<(Self, PushPayload), serde_json::Error> {
+        let (cmd, payload) = framing::decode(bytes)?;
+        Ok((cmd, PushPayload::from_bytes(payload)?))
+    }
+
+    /// `None` for commands that touch every channel
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            ChannelCommand::Create { channel }
+            | ChannelCommand::Destroy { channel }
+            | ChannelCommand::Join { channel, .. }
+            | ChannelCommand::Leave { channel, .. }
+            | ChannelCommand::Broadcast { channel, .. } => Some(channel),
+            ChannelCommand::LeaveAll { .. } => None,
+        }
+    }
+}
+
+/// Drives the channels of all gates from a backend server.
+#[derive(Clone, Debug)]
+pub struct ChannelClient {
+    nats: NatsClient,
+}
+
+impl ChannelClient {
+    pub fn new(nats: NatsClient) -> Self {
+        ChannelClient { nats }
+    }
+
+    pub async fn create(&self, channel: impl Into<String>) {
+        self.send(ChannelCommand::Create {
+            channel: channel.into(),
+        })
+        .await;
+    }
+
+    pub async fn destroy(&self, channel: impl Into<String>) {
+        self.send(ChannelCommand::Destroy {
+            channel: channel.into(),
+        })
+        .await;
+    }
+
+    /// creates the channel if it does not exist
+    pub async fn join(&self, channel: impl Into<String>, uid: impl Into<String>) {
+        self.send(ChannelCommand::Join {
+            channel: channel.into(),
+            uid: uid.into(),
+        })
+        .await;
+    }
+
+    pub async fn leave(&self, channel: impl Into<String>, uid: impl Into<String>) {
+        self.send(ChannelCommand::Leave {
+            channel: channel.into(),
+            uid: uid.into(),
+        })
+        .await;
+    }
+
+    pub async fn leave_all(&self, uid: impl Into<String>) {
+        self.send(ChannelCommand::LeaveAll { uid: uid.into() })
+            .await;
+    }
+
+    pub async fn broadcast(
+        &self,
+        channel: impl Into<String>,
+        route: impl Into<String>,
+        payload: PushPayload,
+    ) {
+        let cmd = ChannelCommand::Broadcast {
+            channel: channel.into(),
+            route: route.into(),
+        };
+        self.publish(cmd, &payload).await;
+    }
+
+    async fn send(&self, cmd: ChannelCommand) {
+        self.publish(cmd, &PushPayload::new()).await;
+    }
+
+    async fn publish(&self, cmd: ChannelCommand, payload: &PushPayload) {
+        let subject = CHANNEL_SUBJECT.to_string();
+        match cmd.channel() {
+            Some(channel) => {
+                let mut headers = HeaderMap::new();
+                headers.insert(CHANNEL_HEADER, channel);
+                self.nats
+                    .publish_with_headers(subject, headers, cmd.encode(payload))
+                    .await
+            }
+            None => self.nats.publish(subject, cmd.encode(payload)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::CodecKind, nats_client::MemoryBus};
+
+    #[test]
+    fn test_encode_decode() {
+        let cmd = ChannelCommand::Broadcast {
+            channel: "guild.1".to_string(),
+            route: "chat.guild.recv".to_string(),
+        };
+        let payload = PushPayload::new().with(CodecKind::Json, Bytes::from(r#""hi""#));
+        let (decoded, decoded_payload) = ChannelCommand::decode(cmd.encode(&payload)).unwrap();
+        assert_eq!(decoded, cmd);
+        assert_eq!(decoded_payload, payload);
+
+        let cmd = ChannelCommand::Join {
+            channel: "guild.1".to_string(),
+            uid: "user1".to_string(),
+        };
+        let (decoded, payload) = ChannelCommand::decode(cmd.encode(&PushPayload::new())).unwrap();
+        assert_eq!(decoded, cmd);
+        assert_eq!(payload, PushPayload::new());
+    }
+
+    #[tokio::test]
+    async fn test_channel_header() {
+        let nats = NatsClient::with_bus(MemoryBus::new());
+        let (sender, mut sent) = tokio::sync::mpsc::unbounded_channel();
+        nats.subscribe(CHANNEL_SUBJECT.to_string(), move |msg| {
+            let channel = from_headers(msg.headers.as_ref()).map(str::to_string);
+            let _ = sender.send(channel);
+        })
+        .await
+        .unwrap();
+        let client = ChannelClient::new(nats);
+        client.join("guild.1", "user1").await;
+        client.leave_all("user1").await;
+        assert_eq!(sent.recv().await.unwrap().as_deref(), Some("guild.1"));
+        assert_eq!(sent.recv().await.unwrap(), None);
+    }
+}
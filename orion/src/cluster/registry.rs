@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, Instant},
+};
+use tracing::{error, info, warn};
+
+use crate::nats_client::{Concurrency, NatsClient, Subscription, SubscriptionError};
+
+/// servers publish [`ClusterEvent`]s here
+pub const ANNOUNCE_SUBJECT: &str = "cluster.announce";
+/// a registry that just started asks every server to announce itself
+pub const DISCOVER_SUBJECT: &str = "cluster.discover";
+/// how often a live server announces itself
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// servers not heard from for this long are dropped
+pub const SERVER_TTL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub id: u32,
+    pub server_type: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+impl ServerInfo {
+    pub fn new(id: u32, server_type: impl Into<String>) -> Self {
+        ServerInfo {
+            id,
+            server_type: server_type.into(),
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Up(ServerInfo),
+    Down { server_type: String, id: u32 },
+}
+
+impl ClusterEvent {
+    pub fn encode(&self) -> Bytes {
+        Bytes::from(serde_json::to_vec(self).expect("cluster event should serialize"))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ServerInfo,
+    last_seen: Instant,
+}
+
+/// The live servers of the cluster by type, kept up to date from the announcements servers
+/// publish over NATS.
+#[derive(Clone, Debug, Default)]
+pub struct ServerRegistry {
+    servers: Arc<RwLock<HashMap<String, BTreeMap<u32, Entry>>>>,
+}
+
+impl ServerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds the server or refreshes it, returns true if it was not known
+    pub fn insert(&self, info: ServerInfo) -> bool {
+        let mut servers = self.servers.write().unwrap();
+        let entry = Entry {
+            last_seen: Instant::now(),
+            info,
+        };
+        servers
+            .entry(entry.info.server_type.clone())
+            .or_default()
+            .insert(entry.info.id, entry)
+            .is_none()
+    }
+
+    pub fn remove(&self, server_type: &str, id: u32) -> bool {
+        let mut servers = self.servers.write().unwrap();
+        let Some(of_type) = servers.get_mut(server_type) else {
+            return false;
+        };
+        let removed = of_type.remove(&id).is_some();
+        if of_type.is_empty() {
+            servers.remove(server_type);
+        }
+        removed
+    }
+
+    /// in id order, so every gate sees the same list
+    pub fn servers(&self, server_type: &str) -> Vec<ServerInfo> {
+        match self.servers.read().unwrap().get(server_type) {
+            Some(of_type) => of_type.values().map(|e| e.info.clone()).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn contains(&self, server_type: &str, id: u32) -> bool {
+        self.servers
+            .read()
+            .unwrap()
+            .get(server_type)
+            .is_some_and(|of_type| of_type.contains_key(&id))
+    }
+
+    /// drops the servers not heard from within `ttl` and returns them
+    pub fn expire(&self, ttl: Duration) -> Vec<ServerInfo> {
+        let now = Instant::now();
+        let mut expired = vec![];
+        let mut servers = self.servers.write().unwrap();
+        for of_type in servers.values_mut() {
+            of_type.retain(|_, entry| {
+                let alive = now.duration_since(entry.last_seen) < ttl;
+                if !alive {
+                    expired.push(entry.info.clone());
+                }
+                alive
+            });
+        }
+        servers.retain(|_, of_type| !of_type.is_empty());
+        expired
+    }
+
+    pub fn apply(&self, event: ClusterEvent) {
+        match event {
+            ClusterEvent::Up(info) => {
+                let (server_type, id) = (info.server_type.clone(), info.id);
+                if self.insert(info) {
+                    info!("Server {} {} joined the cluster", server_type, id);
+                }
+            }
+            ClusterEvent::Down { server_type, id } => {
+                if self.remove(&server_type, id) {
+                    info!("Server {} {} left the cluster", server_type, id);
+                }
+            }
+        }
+    }
+
+    /// follows the announcements and drops servers whose heartbeats stop
+    pub async fn watch(&self, nats: &NatsClient) -> Result<(), SubscriptionError> {
+        let registry = self.clone();
+        nats.subscribe(
+            ANNOUNCE_SUBJECT.to_string(),
+            move |msg| match ClusterEvent::decode(&msg.payload) {
+                Ok(event) => registry.apply(event),
+                Err(e) => error!("Failed to decode cluster event: {}", e),
+            },
+        )
+        .await?;
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(HEARTBEAT_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for server in registry.expire(SERVER_TTL) {
+                    info!("Server {} {} timed out", server.server_type, server.id);
+                }
+            }
+        });
+        nats.publish(DISCOVER_SUBJECT.to_string(), Bytes::new())
+            .await;
+        Ok(())
+    }
+}
+
+/// announces this server now, on every heartbeat and whenever a registry asks
+pub async fn announce(
+    nats: &NatsClient,
+    info: ServerInfo,
+) -> Result<Announcement, SubscriptionError> {
+    let event = ClusterEvent::Up(info.clone()).encode();
+    let responder = nats.clone();
+    let reply = event.clone();
+    let discover = nats
+        .subscribe_async(
+            DISCOVER_SUBJECT.to_string(),
+            Concurrency::Bounded(1),
+            move |_| {
+                let responder = responder.clone();
+                let reply = reply.clone();
+                async move {
+                    responder.publish(ANNOUNCE_SUBJECT.to_string(), reply).await;
+                }
+            },
+        )
+        .await?;
+    let heartbeat_nats = nats.clone();
+    let heartbeat = tokio::spawn(async move {
+        let mut ticker = interval(HEARTBEAT_INTERVAL);
+        loop {
+            ticker.tick().await;
+            heartbeat_nats
+                .publish(ANNOUNCE_SUBJECT.to_string(), event.clone())
+                .await;
+        }
+    });
+    Ok(Announcement {
+        info,
+        nats: nats.clone(),
+        heartbeat,
+        discover,
+    })
+}
+
+/// Keeps a server announced, dropping it does not stop the announcements.
+pub struct Announcement {
+    info: ServerInfo,
+    nats: NatsClient,
+    heartbeat: JoinHandle<()>,
+    discover: Subscription,
+}
+
+impl Announcement {
+    pub fn info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// stops announcing and tells the registries this server is going away, instead of
+    /// waiting for it to time out
+    pub async fn leave(self) {
+        self.heartbeat.abort();
+        if let Err(e) = self.discover.unsubscribe().await {
+            warn!("Failed to stop answering discovery: {}", e);
+        }
+        let event = ClusterEvent::Down {
+            server_type: self.info.server_type.clone(),
+            id: self.info.id,
+        };
+        self.nats
+            .publish(ANNOUNCE_SUBJECT.to_string(), event.encode())
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let registry = ServerRegistry::new();
+        assert!(registry.insert(ServerInfo::new(2, "area")));
+        assert!(registry.insert(ServerInfo::new(1, "area")));
+        assert!(!registry.insert(ServerInfo::new(1, "area")));
+        assert!(registry.insert(ServerInfo::new(1, "chat")));
+        let ids: Vec<u32> = registry.servers("area").iter().map(|s| s.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(registry.contains("chat", 1));
+        assert!(registry.remove("chat", 1));
+        assert!(!registry.remove("chat", 1));
+        assert!(registry.servers("chat").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire() {
+        let registry = ServerRegistry::new();
+        registry.insert(ServerInfo::new(1, "area"));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        registry.insert(ServerInfo::new(2, "area"));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let expired = registry.expire(SERVER_TTL);
+        assert_eq!(expired, [ServerInfo::new(1, "area")]);
+        assert!(registry.contains("area", 2));
+    }
+
+    #[test]
+    fn test_events() {
+        let registry = ServerRegistry::new();
+        let up = ClusterEvent::Up(ServerInfo::new(3, "battle"));
+        registry.apply(ClusterEvent::decode(&up.encode()).unwrap());
+        assert!(registry.contains("battle", 3));
+        let down = ClusterEvent::Down {
+            server_type: "battle".to_string(),
+            id: 3,
+        };
+        registry.apply(ClusterEvent::decode(&down.encode()).unwrap());
+        assert!(!registry.contains("battle", 3));
+    }
+}
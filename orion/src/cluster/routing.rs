@@ -0,0 +1,438 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{envelope, route::fnv1a, session::Session};
+
+use super::registry::{ServerInfo, ServerRegistry};
+
+/// what a strategy may look at to pick a server
+pub struct RouteContext<'a> {
+    pub uid: &'a str,
+    pub route: &'a str,
+    pub session: &'a Session,
+}
+
+/// Picks one of the live servers of a type for a message.
+pub trait RoutingStrategy: Send + Sync {
+    /// `servers` is never empty and is in id order
+    fn select(&self, ctx: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32>;
+
+    /// the session key the gate records the selected server under, for strategies that
+    /// keep a player on one server
+    fn binding_key(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// custom strategies can be plain closures
+impl<F> RoutingStrategy for F
+where
+    F: Fn(&RouteContext<'_>, &[ServerInfo]) -> Option<u32> + Send + Sync,
+{
+    fn select(&self, ctx: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32> {
+        self(ctx, servers)
+    }
+}
+
+pub struct Random;
+
+impl RoutingStrategy for Random {
+    fn select(&self, _: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32> {
+        servers.get(fastrand::usize(..servers.len())).map(|s| s.id)
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RoutingStrategy for RoundRobin {
+    fn select(&self, _: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        servers.get(n % servers.len()).map(|s| s.id)
+    }
+}
+
+/// Rendezvous hashing on the uid: a player keeps its server as long as that server is up,
+/// and only the players of a server that goes away move.
+pub struct ConsistentHash;
+
+impl RoutingStrategy for ConsistentHash {
+    fn select(&self, ctx: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32> {
+        servers
+            .iter()
+            .max_by_key(|s| fnv1a(format!("{}:{}", s.id, ctx.uid).as_bytes()))
+            .map(|s| s.id)
+    }
+}
+
+/// the server id stored in the session under `key` while that server is up, `fallback`
+/// otherwise
+pub struct Sticky {
+    key: String,
+    fallback: Box<dyn RoutingStrategy>,
+}
+
+impl Sticky {
+    pub fn new(key: impl Into<String>, fallback: impl RoutingStrategy + 'static) -> Self {
+        Sticky {
+            key: key.into(),
+            fallback: Box::new(fallback),
+        }
+    }
+}
+
+impl RoutingStrategy for Sticky {
+    fn select(&self, ctx: &RouteContext<'_>, servers: &[ServerInfo]) -> Option<u32> {
+        match ctx.session.get::<u32>(&self.key) {
+            Some(id) if servers.iter().any(|s| s.id == id) => Some(id),
+            _ => self.fallback.select(ctx, servers),
+        }
+    }
+
+    fn binding_key(&self) -> Option<&str> {
+        Some(&self.key)
+    }
+}
+
+/// session key of the server of `server_type` a player is bound to
+pub fn binding_key(server_type: &str) -> String {
+    format!("{}_server", server_type)
+}
+
+/// where a message goes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub server_type: String,
+    /// `None` for server types without a rule, any of their servers may take it
+    pub server_id: Option<u32>,
+    /// set when the session has to be bound to `server_id`
+    pub binding: Option<Binding>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub key: String,
+    pub server_id: u32,
+    /// the server the session was bound to, if it left the cluster
+    pub lost: Option<u32>,
+}
+
+impl Target {
+    pub fn subject(&self) -> String {
+        match self.server_id {
+            Some(id) => envelope::instance_subject(&self.server_type, id),
+            None => envelope::subject(&self.server_type),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoutingError {
+    /// no live server of the type
+    NoServer(String),
+    /// the strategy declined every server
+    NotSelected(String),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::NoServer(t) => write!(f, "no live {} server", t),
+            RoutingError::NotSelected(t) => write!(f, "no {} server selected", t),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+struct Rule {
+    prefix: String,
+    server_type: String,
+    strategy: Box<dyn RoutingStrategy>,
+}
+
+/// Maps route prefixes to the server type that owns them and the strategy picking one of
+/// its live servers from the registry. Routes without a rule go to the server type named by
+/// their first segment, as any instance.
+pub struct RoutingTable {
+    registry: ServerRegistry,
+    rules: Vec<Rule>,
+}
+
+impl RoutingTable {
+    pub fn new(registry: ServerRegistry) -> Self {
+        RoutingTable {
+            registry,
+            rules: vec![],
+        }
+    }
+
+    /// `prefix` matches whole segments, `area` matches `area.player.move` but not
+    /// `arena.join`. The longest matching prefix wins.
+    pub fn route(
+        mut self,
+        prefix: impl Into<String>,
+        server_type: impl Into<String>,
+        strategy: impl RoutingStrategy + 'static,
+    ) -> Self {
+        self.rules.push(Rule {
+            prefix: prefix.into(),
+            server_type: server_type.into(),
+            strategy: Box::new(strategy),
+        });
+        self
+    }
+
+    /// keeps each player on the server of `server_type` recorded in its session, `fallback`
+    /// picks one for players not bound yet or whose server left
+    pub fn sticky(
+        self,
+        prefix: impl Into<String>,
+        server_type: impl Into<String>,
+        fallback: impl RoutingStrategy + 'static,
+    ) -> Self {
+        let server_type = server_type.into();
+        let strategy = Sticky::new(binding_key(&server_type), fallback);
+        self.route(prefix, server_type, strategy)
+    }
+
+    pub fn registry(&self) -> &ServerRegistry {
+        &self.registry
+    }
+
+    fn rule(&self, route: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                route == rule.prefix
+                    || route
+                        .strip_prefix(rule.prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with('.'))
+            })
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    pub fn server_type<'a>(&'a self, route: &'a str) -> &'a str {
+        match self.rule(route) {
+            Some(rule) => &rule.server_type,
+            None => route.split('.').next().unwrap_or(route),
+        }
+    }
+
+    pub fn resolve(&self, ctx: &RouteContext<'_>) -> Result<Target, RoutingError> {
+        let Some(rule) = self.rule(ctx.route) else {
+            return Ok(Target {
+                server_type: self.server_type(ctx.route).to_string(),
+                server_id: None,
+                binding: None,
+            });
+        };
+        let servers = self.registry.servers(&rule.server_type);
+        if servers.is_empty() {
+            return Err(RoutingError::NoServer(rule.server_type.clone()));
+        }
+        let id = rule
+            .strategy
+            .select(ctx, &servers)
+            .ok_or_else(|| RoutingError::NotSelected(rule.server_type.clone()))?;
+        let binding = rule.strategy.binding_key().and_then(|key| {
+            let bound = ctx.session.get::<u32>(key);
+            (bound != Some(id)).then(|| Binding {
+                key: key.to_string(),
+                server_id: id,
+                lost: bound.filter(|b| !servers.iter().any(|s| s.id == *b)),
+            })
+        });
+        Ok(Target {
+            server_type: rule.server_type.clone(),
+            server_id: Some(id),
+            binding,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn registry(server_type: &str, ids: &[u32]) -> ServerRegistry {
+        let registry = ServerRegistry::new();
+        for id in ids {
+            registry.insert(ServerInfo::new(*id, server_type));
+        }
+        registry
+    }
+
+    fn ctx<'a>(uid: &'a str, route: &'a str, session: &'a Session) -> RouteContext<'a> {
+        RouteContext {
+            uid,
+            route,
+            session,
+        }
+    }
+
+    #[test]
+    fn test_prefix_rules() {
+        let table = RoutingTable::new(registry("battle", &[1]))
+            .route("area", "area", Random)
+            .route("area.battle", "battle", Random);
+        let session = Session::default();
+        assert_eq!(table.server_type("area.battle.cast"), "battle");
+        assert_eq!(table.server_type("area.player.move"), "area");
+        assert_eq!(table.server_type("arena.join"), "arena");
+        assert_eq!(
+            table.resolve(&ctx("u", "area.battle.cast", &session)),
+            Ok(Target {
+                server_type: "battle".to_string(),
+                server_id: Some(1),
+                binding: None,
+            })
+        );
+        assert_eq!(
+            table.resolve(&ctx("u", "area.player.move", &session)),
+            Err(RoutingError::NoServer("area".to_string()))
+        );
+        let target = table
+            .resolve(&ctx("u", "chat.world.send", &session))
+            .unwrap();
+        assert_eq!(target.server_id, None);
+        assert_eq!(target.subject(), "server.chat");
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let table = RoutingTable::new(registry("chat", &[1, 2, 3])).route(
+            "chat",
+            "chat",
+            RoundRobin::new(),
+        );
+        let session = Session::default();
+        let picked: Vec<u32> = (0..6)
+            .map(|_| {
+                table
+                    .resolve(&ctx("u", "chat.send", &session))
+                    .unwrap()
+                    .server_id
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(picked, [1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_random() {
+        let servers = registry("chat", &[1, 2]).servers("chat");
+        let session = Session::default();
+        let picked: HashSet<u32> = (0..100)
+            .filter_map(|_| Random.select(&ctx("u", "chat.send", &session), &servers))
+            .collect();
+        assert_eq!(picked, HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let reg = registry("area", &[1, 2, 3, 4]);
+        let session = Session::default();
+        let uids: Vec<String> = (0..200).map(|i| format!("user{}", i)).collect();
+        let before: Vec<u32> = uids
+            .iter()
+            .map(|uid| {
+                ConsistentHash
+                    .select(&ctx(uid, "area.move", &session), &reg.servers("area"))
+                    .unwrap()
+            })
+            .collect();
+        assert!(before.iter().collect::<HashSet<_>>().len() > 1);
+        reg.remove("area", 4);
+        for (uid, server) in uids.iter().zip(before) {
+            let after = ConsistentHash
+                .select(&ctx(uid, "area.move", &session), &reg.servers("area"))
+                .unwrap();
+            // only the players of the server that left move
+            if server != 4 {
+                assert_eq!(after, server);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sticky() {
+        let servers = registry("area", &[1, 2]).servers("area");
+        let mut session = Session::default();
+        let sticky = Sticky::new("area_id", |_: &RouteContext<'_>, _: &[ServerInfo]| Some(1));
+        session.set("area_id", 2).unwrap();
+        assert_eq!(
+            sticky.select(&ctx("u", "area.move", &session), &servers),
+            Some(2)
+        );
+        // the bound server is gone
+        session.set("area_id", 9).unwrap();
+        assert_eq!(
+            sticky.select(&ctx("u", "area.move", &session), &servers),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_binding() {
+        let reg = registry("area", &[1, 2]);
+        let table = RoutingTable::new(reg.clone()).sticky("area", "area", RoundRobin::new());
+        let key = binding_key("area");
+        let mut session = Session::default();
+        let target = table.resolve(&ctx("u", "area.move", &session)).unwrap();
+        assert_eq!(
+            target.binding,
+            Some(Binding {
+                key: key.clone(),
+                server_id: 1,
+                lost: None,
+            })
+        );
+        session.set(&key, 1).unwrap();
+        // bound players stay put and need no new binding
+        for _ in 0..3 {
+            let target = table.resolve(&ctx("u", "area.move", &session)).unwrap();
+            assert_eq!(target.server_id, Some(1));
+            assert_eq!(target.binding, None);
+        }
+        reg.remove("area", 1);
+        let target = table.resolve(&ctx("u", "area.move", &session)).unwrap();
+        assert_eq!(target.server_id, Some(2));
+        assert_eq!(target.binding.unwrap().lost, Some(1));
+    }
+
+    #[test]
+    fn test_custom() {
+        let table = RoutingTable::new(registry("area", &[1, 2])).route(
+            "area",
+            "area",
+            |ctx: &RouteContext<'_>, servers: &[ServerInfo]| {
+                (ctx.uid == "gm").then(|| servers.last().unwrap().id)
+            },
+        );
+        let session = Session::default();
+        assert_eq!(
+            table
+                .resolve(&ctx("gm", "area.move", &session))
+                .unwrap()
+                .server_id,
+            Some(2)
+        );
+        assert_eq!(
+            table.resolve(&ctx("u", "area.move", &session)),
+            Err(RoutingError::NotSelected("area".to_string()))
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug)]
+pub enum CodecError {
+    Encode(String),
+    Decode(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode(e) => write!(f, "failed to encode message: {}", e),
+            CodecError::Decode(e) => write!(f, "failed to decode message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Turns message payloads into typed structs and back.
+pub trait Codec<T>: Send + Sync {
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        serde_json::to_vec(value)
+            .map(Bytes::from)
+            .map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtobufCodec;
+
+impl<T: prost::Message + Default> Codec<T> for ProtobufCodec {
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        Ok(Bytes::from(value.encode_to_vec()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        T::decode(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+/// The codec a client speaks, chosen per deployment or in the handshake. The gate passes it
+/// to the backends in every [`Envelope`](crate::envelope::Envelope).
+///
+/// It is a codec itself for types that are both prost messages and serde types, so a
+/// handler can decode whatever the client sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    #[default]
+    Json,
+    Protobuf,
+}
+
+impl CodecKind {
+    pub const ALL: [CodecKind; 2] = [CodecKind::Json, CodecKind::Protobuf];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CodecKind::Json => "json",
+            CodecKind::Protobuf => "protobuf",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+impl<T> Codec<T> for CodecKind
+where
+    T: Serialize + DeserializeOwned + prost::Message + Default,
+{
+    fn encode(&self, value: &T) -> Result<Bytes, CodecError> {
+        match self {
+            CodecKind::Json => JsonCodec.encode(value),
+            CodecKind::Protobuf => ProtobufCodec.encode(value),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            CodecKind::Json => JsonCodec.decode(bytes),
+            CodecKind::Protobuf => ProtobufCodec.decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    struct Move {
+        #[prost(int32, tag = "1")]
+        x: i32,
+        #[prost(int32, tag = "2")]
+        y: i32,
+        #[prost(string, tag = "3")]
+        #[serde(default)]
+        facing: String,
+    }
+
+    fn sample() -> Move {
+        Move {
+            x: 3,
+            y: -7,
+            facing: "north".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let bytes = JsonCodec.encode(&sample()).unwrap();
+        assert_eq!(&bytes[..], br#"{"x":3,"y":-7,"facing":"north"}"#);
+        let decoded: Move = JsonCodec.decode(&bytes).unwrap();
+        assert_eq!(decoded, sample());
+        assert!(matches!(
+            Codec::<Move>::decode(&JsonCodec, b"{"),
+            Err(CodecError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_protobuf() {
+        let bytes = ProtobufCodec.encode(&sample()).unwrap();
+        let decoded: Move = ProtobufCodec.decode(&bytes).unwrap();
+        assert_eq!(decoded, sample());
+        assert!(matches!(
+            Codec::<Move>::decode(&ProtobufCodec, b"\xff"),
+            Err(CodecError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_kind() {
+        for kind in CodecKind::ALL {
+            assert_eq!(CodecKind::from_name(kind.name()), Some(kind));
+            let bytes = kind.encode(&sample()).unwrap();
+            let decoded: Move = kind.decode(&bytes).unwrap();
+            assert_eq!(decoded, sample());
+        }
+        assert_eq!(CodecKind::from_name("msgpack"), None);
+        assert_eq!(
+            serde_json::to_string(&CodecKind::Protobuf).unwrap(),
+            r#""protobuf""#
+        );
+    }
+}
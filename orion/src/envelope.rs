@@ -0,0 +1,147 @@
+use async_nats::HeaderMap;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, CodecError, CodecKind},
+    framing,
+    session::Session,
+};
+
+/// A client message forwarded by a gate to a backend server.
+///
+/// Requests are sent as NATS requests and the reply payload is the response body,
+/// notifications are published. A backend that fails a request replies with
+/// [`ERROR_HEADER`] set instead.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub gate_id: u32,
+    pub uid: String,
+    pub route: String,
+    /// set for requests, the id the response must carry
+    pub msg_id: Option<u32>,
+    pub session: Session,
+    /// the codec the client encodes payloads with
+    #[serde(default)]
+    pub codec: CodecKind,
+    #[serde(skip)]
+    pub payload: Bytes,
+}
+
+/// NATS header of the reply to a request the backend failed to handle, holds the reason
+pub const ERROR_HEADER: &str = "Orion-Error";
+
+/// the reason a backend gave for failing a request, from the headers of its reply
+pub fn reply_error(headers: Option<&HeaderMap>) -> Option<&str> {
+    headers?.get(ERROR_HEADER).map(|reason| reason.as_str())
+}
+
+/// NATS header holding the uid of the envelope, so servers can order the messages of a
+/// player without decoding them
+pub const UID_HEADER: &str = "Orion-Uid";
+
+/// the uid from the headers an envelope was sent with
+pub fn uid(headers: Option<&HeaderMap>) -> Option<&str> {
+    headers?.get(UID_HEADER).map(|uid| uid.as_str())
+}
+
+/// subject the backend servers of `server_type` subscribe to
+pub fn subject(server_type: &str) -> String {
+    format!("server.{}", server_type)
+}
+
+/// subject of one server, for messages routed to a chosen instance
+pub fn instance_subject(server_type: &str, server_id: u32) -> String {
+    format!("server.{}.{}", server_type, server_id)
+}
+
+impl Envelope {
+    /// json header followed by the raw payload
+    pub fn encode(&self) -> Bytes {
+        framing::encode(self, &self.payload)
+    }
+
+    pub fn decode(bytes: Bytes) -> Result<Self, serde_json::Error> {
+        let (mut envelope, payload): (Envelope, Bytes) = framing::decode(bytes)?;
+        envelope.payload = payload;
+        Ok(envelope)
+    }
+
+    /// the headers to send the envelope with
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(UID_HEADER, self.uid.as_str());
+        headers
+    }
+
+    /// the payload as the struct the handler expects, in whatever codec the client speaks
+    pub fn decode_payload<T>(&self) -> Result<T, CodecError>
+    where
+        CodecKind: Codec<T>,
+    {
+        self.codec.decode(&self.payload)
+    }
+
+    /// a response body in the codec of the client
+    pub fn encode_reply<T>(&self, value: &T) -> Result<Bytes, CodecError>
+    where
+        CodecKind: Codec<T>,
+    {
+        self.codec.encode(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut session = Session::default();
+        session.set("area", 3).unwrap();
+        let envelope = Envelope {
+            gate_id: 1,
+            uid: "user1".to_string(),
+            route: "area.player.move".to_string(),
+            msg_id: Some(7),
+            session,
+            codec: CodecKind::Protobuf,
+            payload: Bytes::from_static(b"\x00\x01binary"),
+        };
+        let decoded = Envelope::decode(envelope.encode()).unwrap();
+        assert_eq!(decoded, envelope);
+        assert_eq!(decoded.session.get::<i32>("area"), Some(3));
+        assert_eq!(uid(Some(&envelope.headers())), Some("user1"));
+        assert_eq!(uid(None), None);
+    }
+
+    #[derive(Clone, PartialEq, prost::Message, Serialize, Deserialize)]
+    struct Attack {
+        #[prost(uint64, tag = "1")]
+        target: u64,
+    }
+
+    #[test]
+    fn test_typed_payload() {
+        for codec in CodecKind::ALL {
+            let mut envelope = Envelope {
+                codec,
+                ..Default::default()
+            };
+            envelope.payload = envelope.encode_reply(&Attack { target: 9 }).unwrap();
+            let decoded = Envelope::decode(envelope.encode()).unwrap();
+            assert_eq!(decoded.codec, codec);
+            assert_eq!(
+                decoded.decode_payload::<Attack>().unwrap(),
+                Attack { target: 9 }
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Envelope::decode(Bytes::from_static(b"\x00")).is_err());
+        assert!(Envelope::decode(Bytes::from_static(b"\x00\x00\x00\x10{}")).is_err());
+        assert!(Envelope::decode(Bytes::from_static(b"\x00\x00\x00\x02{x")).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// format:
+///
+/// +---------------+-------------+---------+
+/// | header length | json header | payload |
+/// +---------------+-------------+---------+
+/// | 4B            | N           | M       |
+/// +---------------+-------------+---------+
+///
+pub(crate) fn encode<T: Serialize>(header: &T, payload: &[u8]) -> Bytes {
+    let header = serde_json::to_vec(header).expect("header should serialize");
+    let mut buf = BytesMut::with_capacity(4 + header.len() + payload.len());
+    buf.put_u32(header.len() as u32);
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(payload);
+    buf.freeze()
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(
+    mut bytes: Bytes,
+) -> Result<(T, Bytes), serde_json::Error> {
+    if bytes.len() < 4 {
+        return Err(serde::de::Error::custom("frame too short"));
+    }
+    let header_len = bytes.get_u32() as usize;
+    if bytes.len() < header_len {
+        return Err(serde::de::Error::custom("frame header truncated"));
+    }
+    let header = bytes.split_to(header_len);
+    Ok((serde_json::from_slice(&header)?, bytes))
+}
@@ -1,12 +1,31 @@
+// lets the code generated by orion-macros refer to `::orion` from inside this crate
+extern crate self as orion;
+
 mod app;
 pub use app::app;
 
 mod net;
+pub use net::idle::IdleSupervisor;
 pub use net::nats_client;
+pub use net::rate_limit::{RateLimit, RateLimitPolicy, TokenBucket};
+pub use net::tcp::accept::TcpMetrics;
 pub use net::tcp::serve_tcp;
 pub use net::tcp::tcp_actors::SocketHandle;
+pub use net::tcp::CloseReason;
 pub use net::tcp::SocketListener;
+pub use net::tcp::TcpConfig;
 
 pub mod async_redis;
+pub mod broadcast;
+pub mod channel;
+pub mod cluster;
+pub mod codec;
+pub mod envelope;
+mod framing;
+pub mod route;
+pub mod rpc;
+pub mod server;
+pub mod session;
 
 pub use orion_macros::init_tracing;
+pub use orion_macros::rpc;
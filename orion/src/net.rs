@@ -1,2 +1,4 @@
+pub mod idle;
 pub mod nats_client;
+pub mod rate_limit;
 pub mod tcp;
@@ -0,0 +1,66 @@
+use std::{future, pin::Pin, time::Duration};
+
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Fires when nothing has been received for `timeout`.
+///
+/// It is polled from the connection's own read loop instead of a separate task, so it goes
+/// away together with the connection.
+pub struct IdleSupervisor {
+    timeout: Option<Duration>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleSupervisor {
+    /// `None` never expires
+    pub fn new(timeout: Option<Duration>) -> Self {
+        IdleSupervisor {
+            timeout,
+            sleep: Box::pin(sleep(timeout.unwrap_or_default())),
+        }
+    }
+
+    /// call on any inbound traffic
+    pub fn reset(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.sleep.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    pub async fn expired(&mut self) {
+        if self.timeout.is_none() {
+            return future::pending().await;
+        }
+        self.sleep.as_mut().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expires() {
+        let mut idle = IdleSupervisor::new(Some(Duration::from_secs(10)));
+        let start = Instant::now();
+        idle.expired().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset() {
+        let mut idle = IdleSupervisor::new(Some(Duration::from_secs(10)));
+        let start = Instant::now();
+        tokio::time::advance(Duration::from_secs(6)).await;
+        idle.reset();
+        idle.expired().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(16));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled() {
+        let mut idle = IdleSupervisor::new(None);
+        let result = tokio::time::timeout(Duration::from_secs(3600), idle.expired()).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,18 +1,114 @@
-use std::time::Duration;
+use std::{fmt, future::Future, sync::Arc};
 
-use async_nats::{Message, RequestErrorKind};
+use async_nats::{connection::State, HeaderMap, Message};
 use bytes::Bytes;
-use futures::StreamExt;
-use tracing::error;
+use futures::{Stream, StreamExt};
+use tokio::sync::watch;
+use tracing::{error, warn};
 
-#[derive(Clone, Debug)]
+mod bus;
+mod connection;
+mod jetstream;
+mod memory;
+mod retry;
+mod subscription;
+pub use bus::{BusSubscriber, MessageBus};
+use connection::ConnectionEvents;
+pub use connection::{ConnectError, ConnectOptions, ConnectionEvent, Credentials};
+pub use jetstream::{
+    ConsumerConfig, Delivery, JetStream, JetStreamError, PullConsumer, PushConsumer, StreamConfig,
+};
+pub use memory::{MemoryBus, MemorySubscriber};
+pub use retry::{ErrorKind, RequestError, RequestHook, RequestMetrics, RetryPolicy};
+pub use subscription::{Concurrency, KeyFn, Subscription, SubscriptionError};
+
+#[derive(Clone)]
 pub struct NatsClient {
-    client: async_nats::Client, // the client itself is an actor handle
+    bus: Arc<dyn MessageBus>,
+    /// the connection behind `bus`, none on an in-memory bus
+    server: Option<async_nats::Client>, // the client itself is an actor handle
+    retry: Arc<RetryPolicy>,
+    hook: Option<Arc<dyn RequestHook>>,
+    events: ConnectionEvents,
+}
+
+impl fmt::Debug for NatsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NatsClient")
+            .field("server", &self.server)
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
 }
 
 impl NatsClient {
+    fn new(client: async_nats::Client, events: ConnectionEvents) -> Self {
+        NatsClient {
+            bus: Arc::new(client.clone()),
+            server: Some(client),
+            retry: Arc::new(RetryPolicy::default()),
+            hook: None,
+            events,
+        }
+    }
+
+    /// a client moving its messages over `bus`, e.g. a [`MemoryBus`] shared by the gate and
+    /// backends of a test; it counts as connected
+    pub fn with_bus(bus: impl MessageBus + 'static) -> Self {
+        let events = ConnectionEvents::new();
+        events.set_connected(true);
+        NatsClient {
+            bus: Arc::new(bus),
+            server: None,
+            retry: Arc::new(RetryPolicy::default()),
+            hook: None,
+            events,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match &self.server {
+            Some(client) => client.connection_state() == State::Connected,
+            None => true,
+        }
+    }
+
+    /// holds true while connected, for code that has to wait out an outage
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        self.events.connected()
+    }
+
+    /// the connection events from now on
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> {
+        self.events.stream()
+    }
+
+    /// the policy of [`try_request`](Self::try_request), for this handle and its clones
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Arc::new(policy);
+        self
+    }
+
+    pub fn with_request_hook(mut self, hook: impl RequestHook + 'static) -> Self {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// durable streams for events that must not be lost while their consumers are down,
+    /// none on an in-memory bus
+    pub fn jetstream(&self) -> Option<JetStream> {
+        self.server.clone().map(JetStream::new)
+    }
+
     pub async fn publish(&self, subject: String, payload: Bytes) {
-        let result = self.client.publish(subject, payload).await;
+        let result = self.bus.publish(subject, None, payload).await;
+        if let Err(e) = result {
+            error!("Failed to publish message: {}", e);
+        }
+    }
+
+    pub async fn publish_with_headers(&self, subject: String, headers: HeaderMap, payload: Bytes) {
+        let result = self.bus.publish(subject, Some(headers), payload).await;
         if let Err(e) = result {
             error!("Failed to publish message: {}", e);
         }
@@ -22,52 +118,180 @@ impl NatsClient {
         &self,
         subject: String,
         payload: Bytes,
-    ) -> Result<Message, &'static str> {
-        for _ in 0..3 {
-            let req = async_nats::Request::new()
-                .payload(payload.clone())
-                .timeout(Some(Duration::from_secs(1)));
-            let result = self.client.send_request(subject.clone(), req).await;
-            if let Err(e) = result {
-                error!("Failed to request message: {}", e);
-                if let RequestErrorKind::NoResponders = e.kind() {
-                    return Err("No responders");
+    ) -> Result<Message, RequestError> {
+        self.try_request_with(subject, payload, &self.retry).await
+    }
+
+    pub async fn try_request_with(
+        &self,
+        subject: String,
+        payload: Bytes,
+        policy: &RetryPolicy,
+    ) -> Result<Message, RequestError> {
+        self.request_with(subject, None, payload, policy).await
+    }
+
+    pub async fn try_request_with_headers(
+        &self,
+        subject: String,
+        headers: HeaderMap,
+        payload: Bytes,
+        policy: &RetryPolicy,
+    ) -> Result<Message, RequestError> {
+        self.request_with(subject, Some(headers), payload, policy)
+            .await
+    }
+
+    async fn request_with(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+        policy: &RetryPolicy,
+    ) -> Result<Message, RequestError> {
+        let attempts = policy.attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .bus
+                .request(
+                    subject.clone(),
+                    headers.clone(),
+                    payload.clone(),
+                    policy.timeout,
+                )
+                .await;
+            let e = match result {
+                Ok(msg) => return Ok(msg),
+                Err(e) => RequestError::new(e, attempt),
+            };
+            if attempt >= attempts || !policy.retries(e.kind()) {
+                if let Some(hook) = &self.hook {
+                    hook.on_failure(&subject, &e);
                 }
-            } else {
-                return Ok(result.unwrap());
+                return Err(e);
+            }
+            warn!("Request to {} failed, retrying: {}", subject, e);
+            if let Some(hook) = &self.hook {
+                hook.on_retry(&subject, &e);
             }
+            tokio::time::sleep(policy.delay(attempt)).await;
+            attempt += 1;
         }
-        Err("Failed to request message")
     }
 
-    pub async fn subscribe<F>(&self, subject: String, callback: F)
+    /// runs `callback` on the reading task, it must not block
+    pub async fn subscribe<F>(&self, subject: String, callback: F) -> Result<(), SubscriptionError>
     where
         F: Fn(Message) + Send + Sync + 'static,
     {
-        let mut subscription = self
-            .client
-            .subscribe(subject)
+        let subscription = self
+            .bus
+            .subscribe(subject, None)
             .await
-            .expect("Failed to subscribe");
-        tokio::spawn({
-            let client = self.client.clone();
-            async move {
-                while let Some(msg) = subscription.next().await {
-                    callback(msg);
-                }
-            }
-        });
+            .map_err(SubscriptionError::Subscribe)?;
+        tokio::spawn(run_callback(subscription, callback));
+        Ok(())
+    }
+
+    /// like [`subscribe`](Self::subscribe), but each message goes to one subscriber of
+    /// `queue_group` only
+    pub async fn queue_subscribe<F>(
+        &self,
+        subject: String,
+        queue_group: String,
+        callback: F,
+    ) -> Result<(), SubscriptionError>
+    where
+        F: Fn(Message) + Send + Sync + 'static,
+    {
+        let subscription = self
+            .bus
+            .subscribe(subject, Some(queue_group))
+            .await
+            .map_err(SubscriptionError::Subscribe)?;
+        tokio::spawn(run_callback(subscription, callback));
+        Ok(())
+    }
+
+    /// runs the async `handler` for every message as `concurrency` allows
+    pub async fn subscribe_async<F, Fut>(
+        &self,
+        subject: String,
+        concurrency: Concurrency,
+        handler: F,
+    ) -> Result<Subscription, SubscriptionError>
+    where
+        F: Fn(Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let subscriber = self
+            .bus
+            .subscribe(subject.clone(), None)
+            .await
+            .map_err(SubscriptionError::Subscribe)?;
+        Ok(Subscription::spawn(
+            subject,
+            subscriber,
+            concurrency,
+            subscription::boxed(handler),
+        ))
+    }
+
+    pub async fn queue_subscribe_async<F, Fut>(
+        &self,
+        subject: String,
+        queue_group: String,
+        concurrency: Concurrency,
+        handler: F,
+    ) -> Result<Subscription, SubscriptionError>
+    where
+        F: Fn(Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let subscriber = self
+            .bus
+            .subscribe(subject.clone(), Some(queue_group))
+            .await
+            .map_err(SubscriptionError::Subscribe)?;
+        Ok(Subscription::spawn(
+            subject,
+            subscriber,
+            concurrency,
+            subscription::boxed(handler),
+        ))
     }
 }
 
-pub async fn connect(url: String) -> NatsClient {
-    let result = async_nats::connect(url).await;
-    match result {
-        Ok(client) => NatsClient { client },
-        Err(e) => {
-            panic!("Failed to connect to NATS server: {}", e);
-        }
+async fn run_callback<F>(mut subscription: Box<dyn BusSubscriber>, callback: F)
+where
+    F: Fn(Message) + Send + Sync + 'static,
+{
+    while let Some(msg) = subscription.next().await {
+        callback(msg);
+    }
+}
+
+pub async fn connect(url: String) -> Result<NatsClient, ConnectError> {
+    connect_with(url, ConnectOptions::default()).await
+}
+
+pub async fn connect_with(
+    url: String,
+    options: ConnectOptions,
+) -> Result<NatsClient, ConnectError> {
+    let events = ConnectionEvents::new();
+    let client = options
+        .build(events.clone())
+        .await?
+        .connect(url)
+        .await
+        .map_err(ConnectError::Connect)?;
+    // the first connect may have happened before anyone listened
+    if client.connection_state() == State::Connected {
+        events.set_connected(true);
     }
+    Ok(NatsClient::new(client, events))
 }
 
 // use std::env;
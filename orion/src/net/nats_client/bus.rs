@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use async_nats::{HeaderMap, Message, PublishError, SubscribeError, Subscriber, UnsubscribeError};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+
+/// What [`NatsClient`](super::NatsClient) needs to move messages: a NATS connection, or a
+/// [`MemoryBus`](super::MemoryBus) for running the cluster in one process.
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    async fn publish(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+    ) -> Result<(), PublishError>;
+
+    async fn request(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<Message, async_nats::RequestError>;
+
+    /// with a queue group, each message goes to one subscriber of the group only
+    async fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> Result<Box<dyn BusSubscriber>, SubscribeError>;
+}
+
+/// The messages of a subscription. After [`unsubscribe`](Self::unsubscribe) the stream
+/// yields what was already received and then ends.
+#[async_trait]
+pub trait BusSubscriber: Stream<Item = Message> + Send + Unpin {
+    async fn unsubscribe(&mut self) -> Result<(), UnsubscribeError>;
+}
+
+#[async_trait]
+impl MessageBus for async_nats::Client {
+    async fn publish(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+    ) -> Result<(), PublishError> {
+        match headers {
+            Some(headers) => self.publish_with_headers(subject, headers, payload).await,
+            None => async_nats::Client::publish(self, subject, payload).await,
+        }
+    }
+
+    async fn request(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<Message, async_nats::RequestError> {
+        let mut req = async_nats::Request::new()
+            .payload(payload)
+            .timeout(Some(timeout));
+        if let Some(headers) = headers {
+            req = req.headers(headers);
+        }
+        self.send_request(subject, req).await
+    }
+
+    async fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> Result<Box<dyn BusSubscriber>, SubscribeError> {
+        let subscriber = match queue_group {
+            Some(group) => self.queue_subscribe(subject, group).await?,
+            None => async_nats::Client::subscribe(self, subject).await?,
+        };
+        Ok(Box::new(subscriber))
+    }
+}
+
+#[async_trait]
+impl BusSubscriber for Subscriber {
+    async fn unsubscribe(&mut self) -> Result<(), UnsubscribeError> {
+        Subscriber::unsubscribe(self).await
+    }
+}
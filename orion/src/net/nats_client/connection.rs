@@ -0,0 +1,180 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use futures::Stream;
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+/// connection events kept for subscribers that fall behind
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    UserPassword {
+        user: String,
+        password: String,
+    },
+    Token(String),
+    /// a `.creds` file holding a user jwt and nkey seed
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// shown in the server's connection list
+    pub name: Option<String>,
+    pub credentials: Option<Credentials>,
+    /// [`connect`](super::connect) returns at once and keeps trying in the background
+    /// instead of failing when the server is down
+    pub retry_on_initial_connect: bool,
+    pub ping_interval: Option<Duration>,
+}
+
+impl ConnectOptions {
+    pub(crate) async fn build(
+        self,
+        events: ConnectionEvents,
+    ) -> Result<async_nats::ConnectOptions, ConnectError> {
+        let mut options = async_nats::ConnectOptions::new();
+        if let Some(name) = self.name {
+            options = options.name(name);
+        }
+        options = match self.credentials {
+            Some(Credentials::UserPassword { user, password }) => {
+                options.user_and_password(user, password)
+            }
+            Some(Credentials::Token(token)) => options.token(token),
+            Some(Credentials::File(path)) => options
+                .credentials_file(path)
+                .await
+                .map_err(ConnectError::Credentials)?,
+            None => options,
+        };
+        if self.retry_on_initial_connect {
+            options = options.retry_on_initial_connect();
+        }
+        if let Some(interval) = self.ping_interval {
+            options = options.ping_interval(interval);
+        }
+        Ok(options.event_callback(move |event| {
+            let events = events.clone();
+            async move { events.emit(event.into()) }
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Credentials(std::io::Error),
+    Connect(async_nats::ConnectError),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Credentials(e) => write!(f, "failed to load nats credentials: {}", e),
+            ConnectError::Connect(e) => write!(f, "failed to connect to nats: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+    /// the server is shutting down, the client moves to another one
+    LameDuck,
+    SlowConsumer(u64),
+    ServerError(String),
+    ClientError(String),
+}
+
+impl From<async_nats::Event> for ConnectionEvent {
+    fn from(event: async_nats::Event) -> Self {
+        match event {
+            async_nats::Event::Connected => ConnectionEvent::Connected,
+            async_nats::Event::Disconnected => ConnectionEvent::Disconnected,
+            async_nats::Event::LameDuckMode => ConnectionEvent::LameDuck,
+            async_nats::Event::SlowConsumer(sid) => ConnectionEvent::SlowConsumer(sid),
+            async_nats::Event::ServerError(e) => ConnectionEvent::ServerError(e.to_string()),
+            async_nats::Event::ClientError(e) => ConnectionEvent::ClientError(e.to_string()),
+        }
+    }
+}
+
+/// fans the events of a connection out and tracks whether it is up
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionEvents {
+    events: broadcast::Sender<ConnectionEvent>,
+    connected: watch::Sender<bool>,
+}
+
+impl ConnectionEvents {
+    pub(crate) fn new() -> Self {
+        ConnectionEvents {
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            connected: watch::channel(false).0,
+        }
+    }
+
+    pub(crate) fn emit(&self, event: ConnectionEvent) {
+        match &event {
+            ConnectionEvent::Connected => {
+                info!("Connected to NATS");
+                self.connected.send_replace(true);
+            }
+            ConnectionEvent::Disconnected => {
+                warn!("Disconnected from NATS");
+                self.connected.send_replace(false);
+            }
+            event => warn!("NATS connection event: {:?}", event),
+        }
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.send_replace(connected);
+    }
+
+    pub(crate) fn connected(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    /// the events from now on, a subscriber that falls behind skips the ones it missed
+    pub(crate) fn stream(&self) -> impl Stream<Item = ConnectionEvent> {
+        futures::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events() {
+        let events = ConnectionEvents::new();
+        let connected = events.connected();
+        let stream = events.stream();
+        futures::pin_mut!(stream);
+        events.emit(ConnectionEvent::Connected);
+        assert!(*connected.borrow());
+        events.emit(async_nats::Event::LameDuckMode.into());
+        events.emit(ConnectionEvent::Disconnected);
+        assert!(!*connected.borrow());
+        assert_eq!(stream.next().await, Some(ConnectionEvent::Connected));
+        assert_eq!(stream.next().await, Some(ConnectionEvent::LameDuck));
+        assert_eq!(stream.next().await, Some(ConnectionEvent::Disconnected));
+    }
+}
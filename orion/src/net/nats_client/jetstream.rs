@@ -0,0 +1,548 @@
+use std::{fmt, marker::PhantomData, time::Duration};
+
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, push, AckPolicy},
+    stream::StorageType,
+    AckKind,
+};
+use futures::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::codec::{Codec, CodecError, JsonCodec};
+
+#[derive(Debug)]
+pub enum JetStreamError {
+    Stream(async_nats::Error),
+    Consumer(async_nats::Error),
+    Publish(async_nats::Error),
+    /// failed to take the next message from a consumer
+    Messages(async_nats::Error),
+    Ack(async_nats::Error),
+    Codec(CodecError),
+}
+
+impl fmt::Display for JetStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JetStreamError::Stream(e) => write!(f, "failed to set up stream: {}", e),
+            JetStreamError::Consumer(e) => write!(f, "failed to set up consumer: {}", e),
+            JetStreamError::Publish(e) => write!(f, "failed to publish event: {}", e),
+            JetStreamError::Messages(e) => write!(f, "failed to receive event: {}", e),
+            JetStreamError::Ack(e) => write!(f, "failed to acknowledge event: {}", e),
+            JetStreamError::Codec(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for JetStreamError {}
+
+impl From<CodecError> for JetStreamError {
+    fn from(e: CodecError) -> Self {
+        JetStreamError::Codec(e)
+    }
+}
+
+/// A stream keeping the events published to its subjects until they are consumed or expire.
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    /// no dots or spaces
+    pub name: String,
+    pub subjects: Vec<String>,
+    /// events older than this are dropped, zero keeps them forever
+    pub max_age: Duration,
+    /// keeps events in memory only, they do not survive a server restart
+    pub memory: bool,
+}
+
+impl StreamConfig {
+    pub fn new(name: impl Into<String>, subjects: Vec<String>) -> Self {
+        StreamConfig {
+            name: name.into(),
+            subjects,
+            max_age: Duration::ZERO,
+            memory: false,
+        }
+    }
+}
+
+/// A durable consumer, it keeps its position while nobody is consuming.
+#[derive(Clone, Debug)]
+pub struct ConsumerConfig {
+    /// no dots or spaces
+    pub durable: String,
+    /// only events published to subjects matching this, empty for every event in the stream
+    pub filter_subject: String,
+    /// deliveries of an event before it is given up on
+    pub max_deliver: i64,
+    /// an event not acknowledged within this time is delivered again
+    pub ack_wait: Duration,
+    /// the waits before the redeliveries that follow an ack timeout, the last one repeated
+    pub backoff: Vec<Duration>,
+}
+
+impl ConsumerConfig {
+    pub fn durable(name: impl Into<String>) -> Self {
+        ConsumerConfig {
+            durable: name.into(),
+            filter_subject: String::new(),
+            max_deliver: 5,
+            ack_wait: Duration::from_secs(30),
+            backoff: Vec::new(),
+        }
+    }
+}
+
+/// Durable event streams on JetStream, events are JSON encoded.
+#[derive(Clone, Debug)]
+pub struct JetStream {
+    context: jetstream::Context,
+}
+
+impl JetStream {
+    pub(crate) fn new(client: async_nats::Client) -> Self {
+        JetStream {
+            context: jetstream::new(client),
+        }
+    }
+
+    /// creates the stream unless it exists, an existing one keeps its config
+    pub async fn ensure_stream(&self, config: StreamConfig) -> Result<(), JetStreamError> {
+        let storage = if config.memory {
+            StorageType::Memory
+        } else {
+            StorageType::File
+        };
+        self.context
+            .get_or_create_stream(jetstream::stream::Config {
+                name: config.name,
+                subjects: config.subjects,
+                max_age: config.max_age,
+                storage,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| JetStreamError::Stream(e.into()))?;
+        Ok(())
+    }
+
+    /// returns once the stream has stored the event, with its sequence in the stream
+    pub async fn publish<T>(&self, subject: String, event: &T) -> Result<u64, JetStreamError>
+    where
+        JsonCodec: Codec<T>,
+    {
+        let payload = JsonCodec.encode(event)?;
+        let ack = self
+            .context
+            .publish(subject, payload)
+            .await
+            .map_err(|e| JetStreamError::Publish(e.into()))?
+            .await
+            .map_err(|e| JetStreamError::Publish(e.into()))?;
+        Ok(ack.sequence)
+    }
+
+    /// a consumer its users fetch events from, every instance with the same durable name
+    /// shares the events out
+    pub async fn pull_consumer<T>(
+        &self,
+        stream: &str,
+        config: ConsumerConfig,
+    ) -> Result<PullConsumer<T>, JetStreamError> {
+        let max_deliver = config.max_deliver;
+        let consumer = self
+            .stream(stream)
+            .await?
+            .get_or_create_consumer(
+                &config.durable.clone(),
+                pull::Config {
+                    durable_name: Some(config.durable),
+                    filter_subject: config.filter_subject,
+                    ack_policy: AckPolicy::Explicit,
+                    max_deliver: config.max_deliver,
+                    ack_wait: config.ack_wait,
+                    backoff: config.backoff,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| JetStreamError::Consumer(e.into()))?;
+        Ok(PullConsumer {
+            consumer,
+            max_deliver,
+            event: PhantomData,
+        })
+    }
+
+    /// a consumer the server pushes events to, instances with the same durable name form a
+    /// queue group and share the events out
+    pub async fn push_consumer<T>(
+        &self,
+        stream: &str,
+        config: ConsumerConfig,
+    ) -> Result<PushConsumer<T>, JetStreamError> {
+        let max_deliver = config.max_deliver;
+        let consumer = self
+            .stream(stream)
+            .await?
+            .get_or_create_consumer(
+                &config.durable.clone(),
+                push::Config {
+                    deliver_subject: format!("deliver.{}.{}", stream, config.durable),
+                    deliver_group: Some(config.durable.clone()),
+                    durable_name: Some(config.durable),
+                    filter_subject: config.filter_subject,
+                    ack_policy: AckPolicy::Explicit,
+                    max_deliver: config.max_deliver,
+                    ack_wait: config.ack_wait,
+                    backoff: config.backoff,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| JetStreamError::Consumer(e.into()))?;
+        Ok(PushConsumer {
+            consumer,
+            max_deliver,
+            event: PhantomData,
+        })
+    }
+
+    async fn stream(&self, name: &str) -> Result<jetstream::stream::Stream, JetStreamError> {
+        self.context
+            .get_stream(name)
+            .await
+            .map_err(|e| JetStreamError::Stream(e.into()))
+    }
+}
+
+pub struct PullConsumer<T> {
+    consumer: jetstream::consumer::Consumer<pull::Config>,
+    max_deliver: i64,
+    event: PhantomData<fn() -> T>,
+}
+
+impl<T> PullConsumer<T>
+where
+    JsonCodec: Codec<T>,
+{
+    /// up to `max` events, waiting at most `expires` for the first of them
+    pub async fn fetch(
+        &self,
+        max: usize,
+        expires: Duration,
+    ) -> Result<Vec<Delivery<T>>, JetStreamError> {
+        let mut batch = self
+            .consumer
+            .fetch()
+            .max_messages(max)
+            .expires(expires)
+            .messages()
+            .await
+            .map_err(|e| JetStreamError::Messages(e.into()))?;
+        let mut deliveries = Vec::new();
+        while let Some(msg) = batch.next().await {
+            let msg = msg.map_err(JetStreamError::Messages)?;
+            match Delivery::new(msg, self.max_deliver).await {
+                Ok(delivery) => deliveries.push(delivery),
+                Err(e) => warn!("Dropped event: {}", e),
+            }
+        }
+        Ok(deliveries)
+    }
+
+    /// every event from now on, fetched as they are consumed
+    pub async fn messages(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Delivery<T>, JetStreamError>>, JetStreamError> {
+        let messages = self
+            .consumer
+            .messages()
+            .await
+            .map_err(|e| JetStreamError::Messages(e.into()))?;
+        Ok(deliveries(messages, self.max_deliver))
+    }
+}
+
+pub struct PushConsumer<T> {
+    consumer: jetstream::consumer::Consumer<push::Config>,
+    max_deliver: i64,
+    event: PhantomData<fn() -> T>,
+}
+
+impl<T> PushConsumer<T>
+where
+    JsonCodec: Codec<T>,
+{
+    pub async fn messages(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Delivery<T>, JetStreamError>>, JetStreamError> {
+        let messages = self
+            .consumer
+            .messages()
+            .await
+            .map_err(|e| JetStreamError::Messages(e.into()))?;
+        Ok(deliveries(messages, self.max_deliver))
+    }
+}
+
+fn deliveries<T, E>(
+    messages: impl Stream<Item = Result<jetstream::Message, E>>,
+    max_deliver: i64,
+) -> impl Stream<Item = Result<Delivery<T>, JetStreamError>>
+where
+    JsonCodec: Codec<T>,
+    E: Into<async_nats::Error>,
+{
+    messages.then(move |msg| async move {
+        let msg = msg.map_err(|e| JetStreamError::Messages(e.into()))?;
+        Delivery::new(msg, max_deliver).await
+    })
+}
+
+/// An event taken from a consumer. It is delivered again after the ack wait unless it is
+/// acknowledged with [`ack`](Self::ack), [`nak`](Self::nak) or [`term`](Self::term).
+pub struct Delivery<T> {
+    event: T,
+    message: jetstream::Message,
+    max_deliver: i64,
+}
+
+impl<T> Delivery<T>
+where
+    JsonCodec: Codec<T>,
+{
+    /// an event that fails to decode never will, so it is terminated
+    async fn new(message: jetstream::Message, max_deliver: i64) -> Result<Self, JetStreamError> {
+        match JsonCodec.decode(&message.payload) {
+            Ok(event) => Ok(Delivery {
+                event,
+                message,
+                max_deliver,
+            }),
+            Err(e) => {
+                if let Err(e) = message.ack_with(AckKind::Term).await {
+                    warn!("Failed to terminate {}: {}", message.subject, e);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl<T> Delivery<T> {
+    pub fn event(&self) -> &T {
+        &self.event
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.message.subject
+    }
+
+    /// how many times the event was delivered, this one included
+    pub fn delivered(&self) -> i64 {
+        self.message.info().map(|info| info.delivered).unwrap_or(1)
+    }
+
+    /// no more deliveries follow if this one is not acknowledged, the last chance to
+    /// record the event elsewhere
+    pub fn is_last_delivery(&self) -> bool {
+        self.max_deliver > 0 && self.delivered() >= self.max_deliver
+    }
+
+    /// the event is handled and not delivered again
+    pub async fn ack(&self) -> Result<(), JetStreamError> {
+        self.message.ack().await.map_err(JetStreamError::Ack)
+    }
+
+    /// the event is delivered again after `delay`, or at once
+    pub async fn nak(&self, delay: Option<Duration>) -> Result<(), JetStreamError> {
+        self.message
+            .ack_with(AckKind::Nak(delay))
+            .await
+            .map_err(JetStreamError::Ack)
+    }
+
+    /// the event cannot be handled and is not delivered again
+    pub async fn term(&self) -> Result<(), JetStreamError> {
+        self.message
+            .ack_with(AckKind::Term)
+            .await
+            .map_err(JetStreamError::Ack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        process::{Child, Command, Stdio},
+    };
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::nats_client::{connect, NatsClient};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Purchase {
+        uid: String,
+        item: u32,
+    }
+
+    /// a nats-server with jetstream on a free port, killed when dropped
+    struct NatsServer {
+        child: Child,
+        dir: std::path::PathBuf,
+    }
+
+    impl Drop for NatsServer {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// run with `cargo test -- --ignored` where nats-server is on the path
+    async fn start() -> (NatsServer, NatsClient) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dir = std::env::temp_dir().join(format!("orion-jetstream-{}", port));
+        let child = Command::new("nats-server")
+            .args(["-js", "-a", "127.0.0.1", "-p", &port.to_string(), "-sd"])
+            .arg(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start nats-server, is it installed?");
+        let server = NatsServer { child, dir };
+        let url = format!("nats://127.0.0.1:{}", port);
+        for _ in 0..50 {
+            if let Ok(nats) = connect(url.clone()).await {
+                return (server, nats);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("nats-server did not come up on {}", url);
+    }
+
+    async fn purchases(nats: &NatsClient) -> JetStream {
+        let js = nats.jetstream().unwrap();
+        js.ensure_stream(StreamConfig::new(
+            "PURCHASES",
+            vec!["events.purchase.>".to_string()],
+        ))
+        .await
+        .unwrap();
+        js
+    }
+
+    fn purchase(item: u32) -> Purchase {
+        Purchase {
+            uid: "u1".to_string(),
+            item,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires nats-server"]
+    async fn test_pull_ack() {
+        let (_server, nats) = start().await;
+        let js = purchases(&nats).await;
+        for item in 1..=3 {
+            let seq = js
+                .publish("events.purchase.u1".to_string(), &purchase(item))
+                .await
+                .unwrap();
+            assert_eq!(seq, item as u64);
+        }
+        let consumer = js
+            .pull_consumer::<Purchase>("PURCHASES", ConsumerConfig::durable("mail"))
+            .await
+            .unwrap();
+        let batch = consumer.fetch(10, Duration::from_secs(1)).await.unwrap();
+        let items: Vec<u32> = batch.iter().map(|d| d.event().item).collect();
+        assert_eq!(items, vec![1, 2, 3]);
+        for delivery in &batch {
+            assert_eq!(delivery.delivered(), 1);
+            delivery.ack().await.unwrap();
+        }
+
+        // the durable consumer picks up where it left off
+        let consumer = js
+            .pull_consumer::<Purchase>("PURCHASES", ConsumerConfig::durable("mail"))
+            .await
+            .unwrap();
+        js.publish("events.purchase.u1".to_string(), &purchase(4))
+            .await
+            .unwrap();
+        let batch = consumer.fetch(10, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].event(), &purchase(4));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires nats-server"]
+    async fn test_redelivery() {
+        let (_server, nats) = start().await;
+        let js = purchases(&nats).await;
+        let config = ConsumerConfig {
+            max_deliver: 2,
+            ..ConsumerConfig::durable("achievements")
+        };
+        let consumer = js
+            .pull_consumer::<Purchase>("PURCHASES", config)
+            .await
+            .unwrap();
+        js.publish("events.purchase.u1".to_string(), &purchase(1))
+            .await
+            .unwrap();
+        js.publish("events.purchase.u1".to_string(), &purchase(2))
+            .await
+            .unwrap();
+
+        let batch = consumer.fetch(2, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(batch.len(), 2);
+        assert!(!batch[0].is_last_delivery());
+        batch[0].nak(None).await.unwrap();
+        batch[1].term().await.unwrap();
+
+        // only the nak'd event comes back, and not after its last delivery
+        let batch = consumer.fetch(2, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].event().item, 1);
+        assert_eq!(batch[0].delivered(), 2);
+        assert!(batch[0].is_last_delivery());
+        batch[0].nak(None).await.unwrap();
+        let batch = consumer.fetch(2, Duration::from_millis(500)).await.unwrap();
+        assert!(batch.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires nats-server"]
+    async fn test_push() {
+        let (_server, nats) = start().await;
+        let js = purchases(&nats).await;
+        let consumer = js
+            .push_consumer::<Purchase>("PURCHASES", ConsumerConfig::durable("rewards"))
+            .await
+            .unwrap();
+        let messages = consumer.messages().await.unwrap();
+        futures::pin_mut!(messages);
+        js.publish("events.purchase.u1".to_string(), &purchase(7))
+            .await
+            .unwrap();
+        let delivery = tokio::time::timeout(Duration::from_secs(2), messages.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.event(), &purchase(7));
+        assert_eq!(delivery.subject(), "events.purchase.u1");
+        delivery.ack().await.unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_nats::{
+    HeaderMap, Message, PublishError, RequestErrorKind, SubscribeError, UnsubscribeError,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use super::bus::{BusSubscriber, MessageBus};
+
+/// A message bus inside the process, for tests and for running a whole cluster in one
+/// process. Subjects match like on NATS: `*` stands for one token and `>` for the rest.
+/// Clones share the bus.
+///
+/// Subscribers buffer without limit, a slow one is never cut off.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBus {
+    inner: Arc<Mutex<Subscribers>>,
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    next_id: u64,
+    subscribers: HashMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    subject: String,
+    queue_group: Option<String>,
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// hands the message to every matching subscriber and to one of each queue group,
+    /// returns how many got it
+    fn deliver(&self, message: Message) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let mut groups: HashMap<&str, Vec<&Entry>> = HashMap::new();
+        let mut delivered = 0;
+        for entry in inner.subscribers.values() {
+            if !matches(&entry.subject, &message.subject) {
+                continue;
+            }
+            match &entry.queue_group {
+                Some(group) => groups.entry(group).or_default().push(entry),
+                None => delivered += entry.tx.send(message.clone()).is_ok() as usize,
+            }
+        }
+        for members in groups.values() {
+            let member = members[fastrand::usize(..members.len())];
+            delivered += member.tx.send(message.clone()).is_ok() as usize;
+        }
+        delivered
+    }
+
+    fn add(&self, subject: String, queue_group: Option<String>) -> MemorySubscriber {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.subscribers.insert(
+            id,
+            Entry {
+                subject,
+                queue_group,
+                tx,
+            },
+        );
+        MemorySubscriber {
+            id,
+            bus: self.clone(),
+            rx,
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.inner.lock().unwrap().subscribers.remove(&id);
+    }
+
+    fn inbox(&self) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        format!("_INBOX.memory.{}", inner.next_id)
+    }
+}
+
+fn message(
+    subject: String,
+    reply: Option<String>,
+    headers: Option<HeaderMap>,
+    payload: Bytes,
+) -> Message {
+    Message {
+        length: subject.len() + payload.len(),
+        subject: subject.into(),
+        reply: reply.map(Into::into),
+        payload,
+        headers,
+        status: None,
+        description: None,
+    }
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    async fn publish(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+    ) -> Result<(), PublishError> {
+        self.deliver(message(subject, None, headers, payload));
+        Ok(())
+    }
+
+    async fn request(
+        &self,
+        subject: String,
+        headers: Option<HeaderMap>,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Result<Message, async_nats::RequestError> {
+        let inbox = self.inbox();
+        let mut replies = self.add(inbox.clone(), None);
+        if self.deliver(message(subject, Some(inbox), headers, payload)) == 0 {
+            return Err(RequestErrorKind::NoResponders.into());
+        }
+        match tokio::time::timeout(timeout, replies.next()).await {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) | Err(_) => Err(RequestErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> Result<Box<dyn BusSubscriber>, SubscribeError> {
+        Ok(Box::new(self.add(subject, queue_group)))
+    }
+}
+
+pub struct MemorySubscriber {
+    id: u64,
+    bus: MemoryBus,
+    rx: mpsc::UnboundedReceiver<Message>,
+}
+
+impl Stream for MemorySubscriber {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[async_trait]
+impl BusSubscriber for MemorySubscriber {
+    async fn unsubscribe(&mut self) -> Result<(), UnsubscribeError> {
+        self.bus.remove(self.id);
+        self.rx.close();
+        Ok(())
+    }
+}
+
+impl Drop for MemorySubscriber {
+    fn drop(&mut self) {
+        self.bus.remove(self.id);
+    }
+}
+
+/// whether `subject` matches the subscription subject `pattern`
+pub fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(s)) if token == s => {}
+            _ => return false,
+        }
+    }
+    subject.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::nats_client::{Concurrency, ErrorKind, NatsClient, RetryPolicy};
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("server.chat", "server.chat"));
+        assert!(!matches("server.chat", "server.chat.1"));
+        assert!(!matches("server.chat.1", "server.chat"));
+        assert!(matches("server.*", "server.chat"));
+        assert!(!matches("server.*", "server.chat.1"));
+        assert!(matches("server.*.1", "server.area.1"));
+        assert!(matches("server.>", "server.area.1"));
+        assert!(matches("server.>", "server.area"));
+        assert!(!matches("server.>", "server"));
+        assert!(!matches("gate.>", "server.area"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let bus = MemoryBus::new();
+        let nats = NatsClient::with_bus(bus.clone());
+        let mut exact = bus
+            .subscribe("server.chat".to_string(), None)
+            .await
+            .unwrap();
+        let mut all = bus.subscribe("server.>".to_string(), None).await.unwrap();
+        nats.publish("server.chat".to_string(), Bytes::from("hi"))
+            .await;
+        nats.publish("server.area.1".to_string(), Bytes::from("move"))
+            .await;
+        assert_eq!(exact.next().await.unwrap().payload, "hi");
+        assert_eq!(all.next().await.unwrap().payload, "hi");
+        assert_eq!(all.next().await.unwrap().payload, "move");
+
+        exact.unsubscribe().await.unwrap();
+        nats.publish("server.chat".to_string(), Bytes::from("gone"))
+            .await;
+        assert!(exact.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue_group() {
+        let bus = MemoryBus::new();
+        let nats = NatsClient::with_bus(bus.clone());
+        let handled = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let handled = handled.clone();
+            nats.queue_subscribe("server.chat".to_string(), "chat".to_string(), move |_| {
+                handled.fetch_add(1, Ordering::SeqCst);
+            })
+            .await
+            .unwrap();
+        }
+        for _ in 0..10 {
+            nats.publish("server.chat".to_string(), Bytes::new()).await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 10);
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let bus = MemoryBus::new();
+        let nats = NatsClient::with_bus(bus.clone());
+        let replier = nats.clone();
+        let subscription = nats
+            .subscribe_async(
+                "server.*".to_string(),
+                Concurrency::Bounded(4),
+                move |msg| {
+                    let nats = replier.clone();
+                    async move {
+                        let reply = format!(
+                            "{} from {}",
+                            String::from_utf8_lossy(&msg.payload),
+                            msg.subject
+                        );
+                        nats.publish(msg.reply.unwrap().to_string(), Bytes::from(reply))
+                            .await;
+                    }
+                },
+            )
+            .await
+            .unwrap();
+        let reply = nats
+            .try_request("server.chat".to_string(), Bytes::from("pong"))
+            .await
+            .unwrap();
+        assert_eq!(reply.payload, "pong from server.chat");
+
+        subscription.drain().await.unwrap();
+        let e = nats
+            .try_request("server.chat".to_string(), Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NoResponders);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_request_timeout() {
+        let bus = MemoryBus::new();
+        let nats = NatsClient::with_bus(bus.clone())
+            .with_retry_policy(RetryPolicy::none(Duration::from_secs(1)));
+        let _silent = bus
+            .subscribe("server.chat".to_string(), None)
+            .await
+            .unwrap();
+        let e = nats
+            .try_request("server.chat".to_string(), Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    NoResponders,
+    TimedOut,
+    Other,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// nobody subscribes to the subject
+    NoResponders {
+        attempts: u32,
+    },
+    TimedOut {
+        attempts: u32,
+    },
+    Failed {
+        attempts: u32,
+        source: async_nats::RequestError,
+    },
+}
+
+impl RequestError {
+    pub(crate) fn new(source: async_nats::RequestError, attempts: u32) -> Self {
+        match source.kind() {
+            async_nats::RequestErrorKind::NoResponders => RequestError::NoResponders { attempts },
+            async_nats::RequestErrorKind::TimedOut => RequestError::TimedOut { attempts },
+            async_nats::RequestErrorKind::Other => RequestError::Failed { attempts, source },
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RequestError::NoResponders { .. } => ErrorKind::NoResponders,
+            RequestError::TimedOut { .. } => ErrorKind::TimedOut,
+            RequestError::Failed { .. } => ErrorKind::Other,
+        }
+    }
+
+    pub fn attempts(&self) -> u32 {
+        match self {
+            RequestError::NoResponders { attempts }
+            | RequestError::TimedOut { attempts }
+            | RequestError::Failed { attempts, .. } => *attempts,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::NoResponders { .. } => write!(f, "no responders"),
+            RequestError::TimedOut { attempts } => {
+                write!(f, "timed out after {} attempts", attempts)
+            }
+            RequestError::Failed { attempts, source } => {
+                write!(f, "failed after {} attempts: {}", attempts, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RequestError::Failed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// How [`NatsClient::try_request`](super::NatsClient::try_request) retries a request.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// tries in total, the first one included
+    pub attempts: u32,
+    pub timeout: Duration,
+    /// wait before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// waits a random time between half the backoff and the full backoff, so callers that
+    /// failed together do not retry together
+    pub jitter: bool,
+    pub retry_on: Vec<ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            timeout: Duration::from_secs(1),
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: true,
+            // no responders will not change in a few milliseconds
+            retry_on: vec![ErrorKind::TimedOut, ErrorKind::Other],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// a single try
+    pub fn none(timeout: Duration) -> Self {
+        RetryPolicy {
+            attempts: 1,
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn retries(&self, kind: ErrorKind) -> bool {
+        self.retry_on.contains(&kind)
+    }
+
+    /// the wait after failed attempt `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.backoff.saturating_mul(factor).min(self.max_backoff);
+        if self.jitter {
+            delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Told about retried and failed requests, for metrics.
+pub trait RequestHook: Send + Sync {
+    fn on_retry(&self, _subject: &str, _error: &RequestError) {}
+    fn on_failure(&self, _subject: &str, _error: &RequestError) {}
+}
+
+/// a [`RequestHook`] counting retries and failures, clones share the counts
+#[derive(Clone, Debug, Default)]
+pub struct RequestMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    retries: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    no_responders: AtomicU64,
+}
+
+impl RequestMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.inner.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.inner.failures.load(Ordering::Relaxed)
+    }
+
+    /// failed requests that timed out
+    pub fn timeouts(&self) -> u64 {
+        self.inner.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// failed requests nobody was subscribed for
+    pub fn no_responders(&self) -> u64 {
+        self.inner.no_responders.load(Ordering::Relaxed)
+    }
+}
+
+impl RequestHook for RequestMetrics {
+    fn on_retry(&self, _: &str, _: &RequestError) {
+        self.inner.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_failure(&self, _: &str, error: &RequestError) {
+        self.inner.failures.fetch_add(1, Ordering::Relaxed);
+        match error.kind() {
+            ErrorKind::TimedOut => self.inner.timeouts.fetch_add(1, Ordering::Relaxed),
+            ErrorKind::NoResponders => self.inner.no_responders.fetch_add(1, Ordering::Relaxed),
+            ErrorKind::Other => 0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(300));
+        assert_eq!(policy.delay(40), Duration::from_millis(300));
+
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn test_retry_on() {
+        let policy = RetryPolicy::default();
+        assert!(policy.retries(ErrorKind::TimedOut));
+        assert!(!policy.retries(ErrorKind::NoResponders));
+        assert_eq!(RetryPolicy::none(Duration::from_secs(1)).attempts, 1);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = RequestMetrics::new();
+        let hook: &dyn RequestHook = &metrics;
+        hook.on_retry("server.chat", &RequestError::TimedOut { attempts: 1 });
+        hook.on_failure("server.chat", &RequestError::TimedOut { attempts: 2 });
+        hook.on_failure("server.chat", &RequestError::NoResponders { attempts: 1 });
+        assert_eq!(metrics.retries(), 1);
+        assert_eq!(metrics.failures(), 2);
+        assert_eq!(metrics.timeouts(), 1);
+        assert_eq!(metrics.no_responders(), 1);
+    }
+}
@@ -0,0 +1,327 @@
+use std::{fmt, future::Future, sync::Arc};
+
+use async_nats::{Message, SubscribeError, UnsubscribeError};
+use futures::{future::BoxFuture, StreamExt};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinHandle,
+};
+use tracing::error;
+
+use super::bus::BusSubscriber;
+use crate::route::fnv1a;
+
+/// messages waiting for each worker of an ordered subscription
+const LANE_CAPACITY: usize = 64;
+
+#[derive(Debug)]
+pub enum SubscriptionError {
+    Subscribe(SubscribeError),
+    Unsubscribe(UnsubscribeError),
+    /// the subscription already ended, its subject was closed
+    Closed,
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionError::Subscribe(e) => write!(f, "failed to subscribe: {}", e),
+            SubscriptionError::Unsubscribe(e) => write!(f, "failed to unsubscribe: {}", e),
+            SubscriptionError::Closed => write!(f, "subscription closed"),
+        }
+    }
+}
+
+impl std::error::Error for SubscriptionError {}
+
+pub type KeyFn = Arc<dyn Fn(&Message) -> String + Send + Sync>;
+
+/// How the async handler of a subscription runs.
+#[derive(Clone)]
+pub enum Concurrency {
+    /// at most this many handlers at once, in any order
+    Bounded(usize),
+    /// Messages with the same key are handled one at a time in the order they arrived,
+    /// messages with other keys alongside them on up to `lanes` workers.
+    Ordered { lanes: usize, key: KeyFn },
+}
+
+impl Concurrency {
+    pub fn ordered(lanes: usize, key: impl Fn(&Message) -> String + Send + Sync + 'static) -> Self {
+        Concurrency::Ordered {
+            lanes,
+            key: Arc::new(key),
+        }
+    }
+}
+
+type Handler = Arc<dyn Fn(Message) -> BoxFuture<'static, ()> + Send + Sync>;
+
+pub(crate) fn boxed<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |msg| Box::pin(handler(msg)))
+}
+
+enum Dispatcher {
+    Bounded {
+        permits: Arc<Semaphore>,
+        max: usize,
+        handler: Handler,
+    },
+    Ordered {
+        key: KeyFn,
+        lanes: Vec<mpsc::Sender<Message>>,
+        workers: Vec<JoinHandle<()>>,
+    },
+}
+
+impl Dispatcher {
+    fn new(concurrency: Concurrency, handler: Handler) -> Self {
+        match concurrency {
+            Concurrency::Bounded(max) => {
+                let max = max.max(1);
+                Dispatcher::Bounded {
+                    permits: Arc::new(Semaphore::new(max)),
+                    max,
+                    handler,
+                }
+            }
+            Concurrency::Ordered { lanes, key } => {
+                let (lanes, workers) = (0..lanes.max(1))
+                    .map(|_| {
+                        let (tx, mut rx) = mpsc::channel::<Message>(LANE_CAPACITY);
+                        let handler = handler.clone();
+                        let worker = tokio::spawn(async move {
+                            while let Some(msg) = rx.recv().await {
+                                handler(msg).await;
+                            }
+                        });
+                        (tx, worker)
+                    })
+                    .unzip();
+                Dispatcher::Ordered {
+                    key,
+                    lanes,
+                    workers,
+                }
+            }
+        }
+    }
+
+    /// waits for a free handler slot, so a slow handler holds back reading
+    async fn dispatch(&self, msg: Message) {
+        match self {
+            Dispatcher::Bounded {
+                permits, handler, ..
+            } => {
+                let permit = permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("permits are never closed");
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    handler(msg).await;
+                    drop(permit);
+                });
+            }
+            Dispatcher::Ordered { key, lanes, .. } => {
+                let lane = fnv1a(key(&msg).as_bytes()) as usize % lanes.len();
+                if lanes[lane].send(msg).await.is_err() {
+                    error!("Dropped message, the worker of lane {} is gone", lane);
+                }
+            }
+        }
+    }
+
+    /// waits for every handler already started
+    async fn finish(self) {
+        match self {
+            Dispatcher::Bounded { permits, max, .. } => {
+                let _ = permits.acquire_many(max as u32).await;
+            }
+            Dispatcher::Ordered { lanes, workers, .. } => {
+                drop(lanes);
+                for worker in workers {
+                    let _ = worker.await;
+                }
+            }
+        }
+    }
+}
+
+enum Stop {
+    Unsubscribe,
+    Drain,
+}
+
+type Control = (Stop, oneshot::Sender<Result<(), UnsubscribeError>>);
+
+/// Handle of a subscription with an async handler. Dropping it leaves the subscription
+/// running.
+pub struct Subscription {
+    subject: String,
+    control: oneshot::Sender<Control>,
+}
+
+impl Subscription {
+    pub(crate) fn spawn(
+        subject: String,
+        subscriber: Box<dyn BusSubscriber>,
+        concurrency: Concurrency,
+        handler: Handler,
+    ) -> Self {
+        let (control, rx) = oneshot::channel();
+        tokio::spawn(read(subscriber, rx, Dispatcher::new(concurrency, handler)));
+        Subscription { subject, control }
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// stops taking messages, handlers already running are not waited for
+    pub async fn unsubscribe(self) -> Result<(), SubscriptionError> {
+        self.stop(Stop::Unsubscribe).await
+    }
+
+    /// stops taking messages and returns once every message received so far is handled
+    pub async fn drain(self) -> Result<(), SubscriptionError> {
+        self.stop(Stop::Drain).await
+    }
+
+    async fn stop(self, stop: Stop) -> Result<(), SubscriptionError> {
+        let (done, result) = oneshot::channel();
+        self.control
+            .send((stop, done))
+            .map_err(|_| SubscriptionError::Closed)?;
+        result
+            .await
+            .map_err(|_| SubscriptionError::Closed)?
+            .map_err(SubscriptionError::Unsubscribe)
+    }
+}
+
+async fn read(
+    mut subscriber: Box<dyn BusSubscriber>,
+    control: oneshot::Receiver<Control>,
+    dispatcher: Dispatcher,
+) {
+    let mut control = Some(control);
+    let (stop, done) = loop {
+        let cmd = async {
+            match control.as_mut() {
+                Some(rx) => rx.await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            msg = subscriber.next() => match msg {
+                Some(msg) => dispatcher.dispatch(msg).await,
+                None => return,
+            },
+            cmd = cmd => match cmd {
+                Ok(cmd) => break cmd,
+                // the handle was dropped, keep going
+                Err(_) => control = None,
+            },
+        }
+    };
+    let result = subscriber.unsubscribe().await;
+    if let (Stop::Drain, Ok(())) = (&stop, &result) {
+        // what arrived before the unsubscribe is still buffered, the stream ends after it
+        while let Some(msg) = subscriber.next().await {
+            dispatcher.dispatch(msg).await;
+        }
+        dispatcher.finish().await;
+    }
+    let _ = done.send(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn message(subject: &str, payload: &str) -> Message {
+        Message {
+            subject: subject.into(),
+            reply: None,
+            payload: Bytes::from(payload.to_string()),
+            headers: None,
+            status: None,
+            description: None,
+            length: 0,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bounded() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let (r, p, d) = (running.clone(), peak.clone(), done.clone());
+        let handler = boxed(move |_| {
+            let (running, peak, done) = (r.clone(), p.clone(), d.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let dispatcher = Dispatcher::new(Concurrency::Bounded(3), handler);
+        for i in 0..10 {
+            dispatcher.dispatch(message("test", &i.to_string())).await;
+        }
+        dispatcher.finish().await;
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ordered() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s = seen.clone();
+        let handler = boxed(move |msg: Message| {
+            let seen = s.clone();
+            async move {
+                let payload = String::from_utf8(msg.payload.to_vec()).unwrap();
+                // later messages of a key finish sooner, unless they wait their turn
+                let delay = 10 - payload[2..].parse::<u64>().unwrap();
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                seen.lock().unwrap().push(payload);
+            }
+        });
+        let concurrency = Concurrency::ordered(4, |msg| msg.subject.to_string());
+        let dispatcher = Dispatcher::new(concurrency, handler);
+        for i in 0..6 {
+            let key = ["a", "b"][i % 2];
+            dispatcher
+                .dispatch(message(key, &format!("{}:{}", key, i)))
+                .await;
+        }
+        dispatcher.finish().await;
+        let seen = seen.lock().unwrap();
+        for key in ["a", "b"] {
+            let of_key: Vec<&String> = seen.iter().filter(|p| p.starts_with(key)).collect();
+            let mut sorted = of_key.clone();
+            sorted.sort_by_key(|p| p[2..].parse::<u32>().unwrap());
+            assert_eq!(of_key, sorted);
+        }
+        assert_eq!(seen.len(), 6);
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Refills `rate` tokens per second up to `burst`.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    fn has(&mut self, n: u32) -> bool {
+        self.refill();
+        self.tokens >= n as f64
+    }
+
+    /// takes `n` tokens if there are enough
+    pub fn try_take(&mut self, n: u32) -> bool {
+        if self.has(n) {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// takes `n` tokens even if that leaves the bucket in debt, returns how long to wait
+    /// until the debt is paid
+    pub fn take(&mut self, n: u32) -> Duration {
+        self.refill();
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 || self.rate == 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// what happens to a packet over the limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// stop reading until the connection is back under the limit
+    #[default]
+    Throttle,
+    Drop,
+    /// close the connection
+    Kick,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Pass,
+    Delay(Duration),
+    Drop,
+    Kick,
+}
+
+/// per connection limits on packets and bytes received
+pub(crate) struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    policy: RateLimitPolicy,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(
+        packets: Option<RateLimit>,
+        bytes: Option<RateLimit>,
+        policy: RateLimitPolicy,
+    ) -> Self {
+        RateLimiter {
+            packets: packets.map(|l| TokenBucket::new(l.per_second, l.burst)),
+            bytes: bytes.map(|l| TokenBucket::new(l.per_second, l.burst)),
+            policy,
+        }
+    }
+
+    pub(crate) fn check(&mut self, pkg_len: usize) -> Verdict {
+        let pkg_len = pkg_len as u32;
+        if let RateLimitPolicy::Throttle = self.policy {
+            let delay = [(&mut self.packets, 1), (&mut self.bytes, pkg_len)]
+                .into_iter()
+                .filter_map(|(bucket, n)| bucket.as_mut().map(|b| b.take(n)))
+                .max()
+                .unwrap_or_default();
+            return if delay.is_zero() {
+                Verdict::Pass
+            } else {
+                Verdict::Delay(delay)
+            };
+        }
+        let within = self.packets.as_mut().is_none_or(|b| b.has(1))
+            && self.bytes.as_mut().is_none_or(|b| b.has(pkg_len));
+        if within {
+            if let Some(b) = self.packets.as_mut() {
+                b.take(1);
+            }
+            if let Some(b) = self.bytes.as_mut() {
+                b.take(pkg_len);
+            }
+            return Verdict::Pass;
+        }
+        match self.policy {
+            RateLimitPolicy::Drop => Verdict::Drop,
+            _ => Verdict::Kick,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(10, 5);
+        for _ in 0..5 {
+            assert!(bucket.try_take(1));
+        }
+        assert!(!bucket.try_take(1));
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(bucket.try_take(5));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_take_debt() {
+        let mut bucket = TokenBucket::new(100, 100);
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        assert_eq!(bucket.take(50), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_policy() {
+        let limit = RateLimit {
+            per_second: 1,
+            burst: 2,
+        };
+        let mut limiter = RateLimiter::new(Some(limit), None, RateLimitPolicy::Drop);
+        assert_eq!(limiter.check(10), Verdict::Pass);
+        assert_eq!(limiter.check(10), Verdict::Pass);
+        assert_eq!(limiter.check(10), Verdict::Drop);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.check(10), Verdict::Pass);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_kick_policy_bytes() {
+        let limit = RateLimit {
+            per_second: 100,
+            burst: 100,
+        };
+        let mut limiter = RateLimiter::new(None, Some(limit), RateLimitPolicy::Kick);
+        assert_eq!(limiter.check(60), Verdict::Pass);
+        assert_eq!(limiter.check(60), Verdict::Kick);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_policy() {
+        let limit = RateLimit {
+            per_second: 10,
+            burst: 1,
+        };
+        let mut limiter = RateLimiter::new(Some(limit), None, RateLimitPolicy::Throttle);
+        assert_eq!(limiter.check(10), Verdict::Pass);
+        assert_eq!(
+            limiter.check(10),
+            Verdict::Delay(Duration::from_millis(100))
+        );
+    }
+}
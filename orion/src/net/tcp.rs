@@ -1,71 +1,215 @@
+pub mod accept;
 pub mod tcp_actors;
 
+use std::{net::SocketAddr, time::Duration};
+
+use accept::{AcceptBackoff, ConnectionLimiter, ConnectionPermit, Rejection, TcpMetrics};
 use bytes::{Bytes, BytesMut};
 use tcp_actors::SocketHandle;
 
+use super::{
+    idle::IdleSupervisor,
+    rate_limit::{RateLimit, RateLimitPolicy, RateLimiter, Verdict},
+};
+
 use tokio::{
     io::AsyncReadExt,
     net::{TcpListener, TcpStream},
     select,
+    sync::watch,
+    time::sleep,
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+#[derive(Clone, Debug, Default)]
+pub struct TcpConfig {
+    /// close connections that send nothing for this long
+    pub idle_timeout: Option<Duration>,
+    /// packets received per connection
+    pub packet_limit: Option<RateLimit>,
+    /// bytes received per connection, counted per packet including its header
+    pub byte_limit: Option<RateLimit>,
+    pub rate_limit_policy: RateLimitPolicy,
+    /// connections over the cap are closed as soon as they are accepted
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// first delay after a failed accept, doubled on every failure in a row
+    pub accept_backoff: Option<Duration>,
+    pub metrics: TcpMetrics,
+    /// packets longer than this, header included, close the connection before their body
+    /// is read. Keep `byte_limit` bursts at least this large or such packets never pass.
+    pub max_packet_size: Option<usize>,
+    /// no new connections are taken while it holds false, open ones are left alone
+    pub accepting: Option<watch::Receiver<bool>>,
+}
+
+const DEFAULT_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// closed by this side through [`SocketHandle::close`] or a failed write
+    Closed,
+    /// the peer closed the connection
+    Eof,
+    ReadError,
+    IdleTimeout,
+    /// kicked by [`RateLimitPolicy::Kick`]
+    RateLimited,
+    /// the peer announced a packet over [`TcpConfig::max_packet_size`]
+    TooLarge,
+}
 
 pub async fn serve_tcp(
     addr: String,
     port: u32,
+    config: TcpConfig,
     event_listener: impl SocketListener + Clone + Send + Sync + 'static,
 ) {
     let listener = TcpListener::bind(addr.clone() + ":" + &port.to_string())
         .await
         .expect("should bind to address");
     info!("Listening on: {}", addr + ":" + &port.to_string());
+    if let (Some(max), Some(limit)) = (config.max_packet_size, config.byte_limit) {
+        if (limit.burst as usize) < max {
+            warn!(
+                "Byte limit burst {} is below the max packet size {}, larger packets are never let through",
+                limit.burst, max
+            );
+        }
+    }
+    let limiter = ConnectionLimiter::new(
+        config.max_connections,
+        config.max_connections_per_ip,
+        config.metrics.clone(),
+    );
+    let mut backoff = AcceptBackoff::new(config.accept_backoff.unwrap_or(DEFAULT_ACCEPT_BACKOFF));
+    let mut accepting = config.accepting.clone();
     loop {
+        if let Some(accepting) = &mut accepting {
+            wait_until_accepting(accepting).await;
+        }
         let result = listener.accept().await;
         match result {
-            Ok((socket, _)) => {
-                listen_for_data(socket, event_listener.clone());
+            Ok((socket, addr)) => {
+                backoff.reset();
+                match limiter.try_acquire(addr.ip()) {
+                    Ok(permit) => {
+                        listen_for_data(socket, addr, permit, &config, event_listener.clone());
+                    }
+                    Err(rejection) => {
+                        let cap = match rejection {
+                            Rejection::Total => "connection",
+                            Rejection::PerIp => "per ip connection",
+                        };
+                        warn!(peer = %addr, "Rejected connection over the {} cap", cap);
+                        drop(socket);
+                    }
+                }
             }
             Err(e) => {
-                error!("Failed to accept connection: {}", e);
+                // usually out of file descriptors, retrying at once would spin
+                config.metrics.accept_error();
+                let delay = backoff.next();
+                error!(
+                    "Failed to accept connection: {}, retrying in {:?}",
+                    e, delay
+                );
+                sleep(delay).await;
             }
         }
     }
 }
 
+async fn wait_until_accepting(accepting: &mut watch::Receiver<bool>) {
+    if *accepting.borrow_and_update() {
+        return;
+    }
+    warn!("Stopped accepting connections");
+    while !*accepting.borrow_and_update() {
+        if accepting.changed().await.is_err() {
+            // nobody can resume it any more, keep serving
+            break;
+        }
+    }
+    info!("Accepting connections again");
+}
+
 fn listen_for_data(
     socket: TcpStream,
+    addr: SocketAddr,
+    permit: ConnectionPermit,
+    config: &TcpConfig,
     mut event_listener: impl SocketListener + Clone + Send + Sync + 'static,
 ) {
     let (mut reader, writer) = socket.into_split();
     let token = CancellationToken::new();
-    let socket_handle = SocketHandle::new(writer, token.clone());
+    let socket_handle = SocketHandle::new(writer, addr, token.clone());
     event_listener.onopen(socket_handle.clone());
+    let mut idle = IdleSupervisor::new(config.idle_timeout);
+    let max_packet_size = config.max_packet_size;
+    let mut limiter = RateLimiter::new(
+        config.packet_limit,
+        config.byte_limit,
+        config.rate_limit_policy,
+    );
     tokio::spawn(async move {
         let mut buffer = BytesMut::with_capacity(1024);
-        let mut pkg_extractor =
-            PackageExtractor::new(event_listener.clone(), socket_handle.clone());
-        loop {
+        let mut pkg_extractor = PackageExtractor::new(max_packet_size);
+        let reason = 'read: loop {
             select! {
                 result = reader.read_buf(&mut buffer) => {
                     match result {
-                        Ok(n) if n != 0 => {
-                            pkg_extractor.process(&buffer, n, 0).await;
-                        }
-                        other => {
-                            if let Err(e) = other {
-                                error!("Failed to read from socket; error = {:?}", e);
+                        Ok(0) => break CloseReason::Eof,
+                        Ok(_) => {
+                            idle.reset();
+                            let pkgs = match pkg_extractor.extract(&buffer) {
+                                Ok(pkgs) => pkgs,
+                                Err(len) => {
+                                    warn!(peer = %addr, len, "Closing connection sending a packet over the size limit");
+                                    socket_handle.close().await;
+                                    break 'read CloseReason::TooLarge;
+                                }
+                            };
+                            buffer.clear();
+                            for pkg in pkgs {
+                                match limiter.check(pkg.len()) {
+                                    Verdict::Pass => {}
+                                    Verdict::Delay(delay) => {
+                                        warn!(peer = %addr, ?delay, "Throttling connection over its rate limit");
+                                        sleep(delay).await;
+                                    }
+                                    Verdict::Drop => {
+                                        warn!(peer = %addr, len = pkg.len(), "Dropped packet over the rate limit");
+                                        continue;
+                                    }
+                                    Verdict::Kick => {
+                                        warn!(peer = %addr, "Kicking connection over its rate limit");
+                                        socket_handle.close().await;
+                                        break 'read CloseReason::RateLimited;
+                                    }
+                                }
+                                event_listener.onmessage(socket_handle.clone(), pkg).await;
                             }
-                            break;
+                        }
+                        Err(e) => {
+                            error!("Failed to read from socket; error = {:?}", e);
+                            break CloseReason::ReadError;
                         }
                     }
                 }
+                _ = idle.expired() => {
+                    info!("Closing idle connection {}", socket_handle.peer_addr());
+                    socket_handle.close().await;
+                    break CloseReason::IdleTimeout;
+                }
                 _ = token.cancelled() => {
-                    break;
+                    break CloseReason::Closed;
                 }
             }
-        }
-        event_listener.onclose(socket_handle).await;
+        };
+        event_listener.onclose(socket_handle, reason).await;
+        drop(permit);
     });
 }
 
@@ -79,6 +223,7 @@ pub trait SocketListener {
     fn onclose(
         &mut self,
         socket_handle: SocketHandle,
+        reason: CloseReason,
     ) -> impl std::future::Future<Output = ()> + Send;
 }
 
@@ -88,72 +233,124 @@ enum ReadState {
 }
 
 const HEADER_SIZE: usize = 4;
-struct PackageExtractor<F: SocketListener> {
+struct PackageExtractor {
     pkg_buffer: BytesMut,
     pkg_buffer_offset: usize, // for header and msg
     state: ReadState,
-    event_listener: F,
-    socket_handle: SocketHandle,
+    max_size: Option<usize>,
 }
 
-impl<F: SocketListener> PackageExtractor<F> {
-    fn new(event_listener: F, socket_handle: SocketHandle) -> Self {
+impl PackageExtractor {
+    fn new(max_size: Option<usize>) -> Self {
         Self {
-            pkg_buffer: BytesMut::with_capacity(HEADER_SIZE),
+            pkg_buffer: BytesMut::zeroed(HEADER_SIZE),
             pkg_buffer_offset: 0,
             state: ReadState::ReadingHeader,
-            event_listener,
-            socket_handle,
+            max_size,
         }
     }
 
-    async fn process(&mut self, bytes: &BytesMut, len: usize, bytes_offset: usize) {
-        let mut pkgs = vec![];
-        self.extract(bytes, len, bytes_offset, &mut pkgs);
-        for pkg in pkgs {
-            self.event_listener
-                .onmessage(self.socket_handle.clone(), pkg)
-                .await;
-        }
-    }
-
-    fn extract(
-        &mut self,
-        bytes: &BytesMut,
-        len: usize,
-        mut bytes_offset: usize,
-        result_pkgs: &mut Vec<Bytes>,
-    ) {
-        let target_size = match self.state {
-            ReadState::ReadingHeader => HEADER_SIZE,
-            ReadState::ReadingBody => self.pkg_buffer.len(),
-        };
-        let data_length_available = len - bytes_offset;
-        let data_length_needed = target_size - self.pkg_buffer_offset;
-        let data_length_to_copy = std::cmp::min(data_length_available, data_length_needed);
-        self.pkg_buffer[self.pkg_buffer_offset..self.pkg_buffer_offset + data_length_to_copy]
-            .copy_from_slice(&bytes[bytes_offset..bytes_offset + data_length_to_copy]);
-        self.pkg_buffer_offset += data_length_to_copy;
-        bytes_offset += data_length_to_copy;
-        if self.pkg_buffer_offset == target_size {
-            match self.state {
-                ReadState::ReadingHeader => {
-                    let msg_length = (self.pkg_buffer[1] as u32) << 16
-                        | (self.pkg_buffer[2] as u32) << 8
-                        | self.pkg_buffer[3] as u32;
-                    self.pkg_buffer.resize(HEADER_SIZE + msg_length as usize, 0);
-                    self.state = ReadState::ReadingBody;
-                }
-                ReadState::ReadingBody => {
-                    result_pkgs.push(self.pkg_buffer.clone().freeze());
-                    self.pkg_buffer.clear();
-                    self.pkg_buffer_offset = 0;
-                    self.state = ReadState::ReadingHeader;
+    /// returns the complete packages, header included, found so far. The error is the size
+    /// of a package over the limit, the extractor is of no use after it.
+    fn extract(&mut self, bytes: &[u8]) -> Result<Vec<Bytes>, usize> {
+        let mut result_pkgs = vec![];
+        let mut bytes_offset = 0;
+        loop {
+            let target_size = match self.state {
+                ReadState::ReadingHeader => HEADER_SIZE,
+                ReadState::ReadingBody => self.pkg_buffer.len(),
+            };
+            let data_length_available = bytes.len() - bytes_offset;
+            let data_length_needed = target_size - self.pkg_buffer_offset;
+            let data_length_to_copy = std::cmp::min(data_length_available, data_length_needed);
+            self.pkg_buffer[self.pkg_buffer_offset..self.pkg_buffer_offset + data_length_to_copy]
+                .copy_from_slice(&bytes[bytes_offset..bytes_offset + data_length_to_copy]);
+            self.pkg_buffer_offset += data_length_to_copy;
+            bytes_offset += data_length_to_copy;
+            if self.pkg_buffer_offset == target_size {
+                match self.state {
+                    ReadState::ReadingHeader => {
+                        let msg_length = (self.pkg_buffer[1] as u32) << 16
+                            | (self.pkg_buffer[2] as u32) << 8
+                            | self.pkg_buffer[3] as u32;
+                        let size = HEADER_SIZE + msg_length as usize;
+                        if self.max_size.is_some_and(|max| size > max) {
+                            return Err(size);
+                        }
+                        self.pkg_buffer.resize(size, 0);
+                        self.state = ReadState::ReadingBody;
+                        // an empty body is complete right away
+                        continue;
+                    }
+                    ReadState::ReadingBody => {
+                        let pkg =
+                            std::mem::replace(&mut self.pkg_buffer, BytesMut::zeroed(HEADER_SIZE));
+                        result_pkgs.push(pkg.freeze());
+                        self.pkg_buffer_offset = 0;
+                        self.state = ReadState::ReadingHeader;
+                    }
                 }
             }
+            if bytes_offset == bytes.len() {
+                return Ok(result_pkgs);
+            }
         }
-        if bytes_offset < len {
-            self.extract(bytes, len, bytes_offset, result_pkgs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(pkt_type: u8, body: &[u8]) -> Vec<u8> {
+        let len = body.len();
+        let mut v = vec![pkt_type, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        v.extend_from_slice(body);
+        v
+    }
+
+    #[test]
+    fn test_extract_whole() {
+        let mut extractor = PackageExtractor::new(None);
+        let mut bytes = pkg(3, b"hello");
+        bytes.extend(pkg(2, b""));
+        bytes.extend(pkg(3, b"world"));
+        let pkgs = extractor.extract(&bytes).unwrap();
+        assert_eq!(pkgs.len(), 3);
+        assert_eq!(&pkgs[0][..], &pkg(3, b"hello")[..]);
+        assert_eq!(&pkgs[1][..], &pkg(2, b"")[..]);
+        assert_eq!(&pkgs[2][..], &pkg(3, b"world")[..]);
+    }
+
+    #[test]
+    fn test_extract_split() {
+        let mut extractor = PackageExtractor::new(None);
+        let bytes = pkg(3, b"hello world");
+        let mut pkgs = vec![];
+        for chunk in bytes.chunks(3) {
+            pkgs.extend(extractor.extract(chunk).unwrap());
         }
+        assert_eq!(pkgs.len(), 1);
+        assert_eq!(&pkgs[0][..], &bytes[..]);
+    }
+
+    #[test]
+    fn test_extract_empty_body() {
+        let mut extractor = PackageExtractor::new(None);
+        let pkgs = extractor.extract(&pkg(2, b"")).unwrap();
+        assert_eq!(pkgs.len(), 1);
+        assert_eq!(pkgs[0].len(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_extract_too_large() {
+        let mut extractor = PackageExtractor::new(Some(HEADER_SIZE + 5));
+        assert_eq!(extractor.extract(&pkg(3, b"hello")).unwrap().len(), 1);
+        // refused on the header, before the body is buffered
+        let bytes = pkg(3, b"hello!");
+        assert_eq!(
+            extractor.extract(&bytes[..HEADER_SIZE]),
+            Err(HEADER_SIZE + 6)
+        );
     }
 }
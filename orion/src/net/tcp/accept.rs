@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Counters of the listener, clone it before passing the config to
+/// [`serve_tcp`](super::serve_tcp) to read them.
+#[derive(Clone, Debug, Default)]
+pub struct TcpMetrics {
+    inner: Arc<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    accepted: AtomicU64,
+    rejected_total: AtomicU64,
+    rejected_per_ip: AtomicU64,
+    accept_errors: AtomicU64,
+    active: AtomicUsize,
+}
+
+impl TcpMetrics {
+    pub fn accepted(&self) -> u64 {
+        self.inner.accepted.load(Ordering::Relaxed)
+    }
+
+    /// closed because of `max_connections`
+    pub fn rejected_total(&self) -> u64 {
+        self.inner.rejected_total.load(Ordering::Relaxed)
+    }
+
+    /// closed because of `max_connections_per_ip`
+    pub fn rejected_per_ip(&self) -> u64 {
+        self.inner.rejected_per_ip.load(Ordering::Relaxed)
+    }
+
+    pub fn accept_errors(&self) -> u64 {
+        self.inner.accept_errors.load(Ordering::Relaxed)
+    }
+
+    /// connections open right now
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn accept_error(&self) {
+        self.inner.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    Total,
+    PerIp,
+}
+
+/// Counts open connections in total and per ip.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionLimiter {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    metrics: TcpMetrics,
+}
+
+/// holds a connection slot until dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    ip: IpAddr,
+    limiter: ConnectionLimiter,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.limiter.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+        self.limiter
+            .metrics
+            .inner
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(
+        max_total: Option<usize>,
+        max_per_ip: Option<usize>,
+        metrics: TcpMetrics,
+    ) -> Self {
+        ConnectionLimiter {
+            max_total,
+            max_per_ip,
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+        }
+    }
+
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+        let metrics = &self.metrics.inner;
+        // only the accept loop acquires, so checking then adding does not race
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if self
+            .max_total
+            .is_some_and(|max| metrics.active.load(Ordering::Relaxed) >= max)
+        {
+            metrics.rejected_total.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection::Total);
+        }
+        let count = per_ip.entry(ip).or_default();
+        if self.max_per_ip.is_some_and(|max| *count >= max) {
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+            metrics.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+            return Err(Rejection::PerIp);
+        }
+        *count += 1;
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        Ok(ConnectionPermit {
+            ip,
+            limiter: self.clone(),
+        })
+    }
+}
+
+/// doubles after every failed accept in a row, up to a second
+pub(crate) struct AcceptBackoff {
+    initial: Duration,
+    current: Duration,
+}
+
+impl AcceptBackoff {
+    pub(crate) fn new(initial: Duration) -> Self {
+        AcceptBackoff {
+            initial,
+            current: initial,
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(MAX_ACCEPT_BACKOFF.max(self.initial));
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_max_total() {
+        let metrics = TcpMetrics::default();
+        let limiter = ConnectionLimiter::new(Some(2), None, metrics.clone());
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        let _second = limiter.try_acquire(ip(2)).unwrap();
+        assert_eq!(limiter.try_acquire(ip(3)).unwrap_err(), Rejection::Total);
+        assert_eq!(metrics.active(), 2);
+        assert_eq!(metrics.rejected_total(), 1);
+        drop(first);
+        assert!(limiter.try_acquire(ip(3)).is_ok());
+        assert_eq!(metrics.accepted(), 3);
+    }
+
+    #[test]
+    fn test_max_per_ip() {
+        let metrics = TcpMetrics::default();
+        let limiter = ConnectionLimiter::new(None, Some(1), metrics.clone());
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        assert_eq!(limiter.try_acquire(ip(1)).unwrap_err(), Rejection::PerIp);
+        let _other = limiter.try_acquire(ip(2)).unwrap();
+        assert_eq!(metrics.rejected_per_ip(), 1);
+        drop(first);
+        assert!(limiter.per_ip.lock().unwrap().get(&ip(1)).is_none());
+        assert!(limiter.try_acquire(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = AcceptBackoff::new(Duration::from_millis(300));
+        assert_eq!(backoff.next(), Duration::from_millis(300));
+        assert_eq!(backoff.next(), Duration::from_millis(600));
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_millis(300));
+    }
+}
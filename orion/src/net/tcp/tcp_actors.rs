@@ -1,4 +1,7 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 use bytes::Bytes;
 use tokio::{
@@ -25,7 +28,11 @@ impl TcpWriteActor {
     async fn handle_message(&mut self, msg: Message) {
         match msg {
             Message::Send(bytes) => {
-                let r = self.writer.write_all(&bytes).await;
+                let mut r = self.writer.write_all(&bytes).await;
+                // batch what is queued, but never leave a message in the buffer
+                if r.is_ok() && self.receiver.is_empty() {
+                    r = self.writer.flush().await;
+                }
                 if let Err(e) = r {
                     error!("Failed to write to socket; error = {:?}", e);
                     self.cancel_token.cancel();
@@ -46,11 +53,16 @@ impl TcpWriteActor {
 #[derive(Clone, Debug)]
 pub struct SocketHandle {
     id: u32,
+    peer_addr: SocketAddr,
     sender: mpsc::Sender<Message>,
 }
 
 impl SocketHandle {
-    pub fn new(writer: OwnedWriteHalf, cancel_token: CancellationToken) -> Self {
+    pub fn new(
+        writer: OwnedWriteHalf,
+        peer_addr: SocketAddr,
+        cancel_token: CancellationToken,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(20);
         let buf_writer = BufWriter::new(writer);
         let write_actor = TcpWriteActor {
@@ -65,7 +77,11 @@ impl SocketHandle {
         if id == u32::MAX {
             ENUMERATOR.store(0, Ordering::SeqCst);
         }
-        SocketHandle { sender, id }
+        SocketHandle {
+            sender,
+            peer_addr,
+            id,
+        }
     }
 
     pub async fn send(&self, message: Bytes) {
@@ -75,6 +91,12 @@ impl SocketHandle {
         }
     }
 
+    /// fails instead of waiting when the queue of the socket is full, so a peer that does
+    /// not read holds up no one, returns false if the message was dropped
+    pub fn try_send(&self, message: Bytes) -> bool {
+        self.sender.try_send(Message::Send(message)).is_ok()
+    }
+
     pub async fn close(&self) {
         let result = self.sender.send(Message::Close).await;
         if let Err(e) = result {
@@ -85,6 +107,10 @@ impl SocketHandle {
     pub fn id(&self) -> u32 {
         self.id
     }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
 }
 
 async fn run_write_actor(mut actor: TcpWriteActor) {
@@ -92,3 +118,32 @@ async fn run_write_actor(mut actor: TcpWriteActor) {
         actor.handle_message(msg).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_try_send_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // the peer never reads, the socket buffers and then the queue fill up
+        let _peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        let (_, writer) = stream.into_split();
+        let socket = SocketHandle::new(writer, addr, CancellationToken::new());
+        let message = Bytes::from(vec![0u8; 1 << 20]);
+        let mut dropped = false;
+        for _ in 0..1000 {
+            if !socket.try_send(message.clone()) {
+                dropped = true;
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(dropped);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use bytes::Bytes;
+
+/// Maps string routes such as `area.player.move` to the compact protocol ids carried in
+/// messages.
+///
+/// The dictionary is written one route per line, `#` starts a comment. Ids are assigned in
+/// order starting from 1, 0 is never a valid id. The version is a hash of the content so
+/// clients can cache the dictionary and only fetch it again when it changes.
+#[derive(Clone, Debug, Default)]
+pub struct RouteDict {
+    routes: Vec<String>,
+    ids: HashMap<String, u16>,
+    version: u32,
+}
+
+#[derive(Debug)]
+pub enum RouteDictError {
+    Io(std::io::Error),
+    Duplicate(String),
+    Invalid(String),
+    TooMany,
+}
+
+impl fmt::Display for RouteDictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteDictError::Io(e) => write!(f, "failed to read route dictionary: {}", e),
+            RouteDictError::Duplicate(route) => write!(f, "duplicate route: {}", route),
+            RouteDictError::Invalid(route) => write!(f, "invalid route: {}", route),
+            RouteDictError::TooMany => write!(f, "too many routes, at most {}", u16::MAX),
+        }
+    }
+}
+
+impl std::error::Error for RouteDictError {}
+
+impl RouteDict {
+    pub fn from_routes<I, S>(routes: I) -> Result<Self, RouteDictError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut dict = RouteDict::default();
+        for route in routes {
+            let route = route.into();
+            if route.is_empty() || route.chars().any(char::is_whitespace) {
+                return Err(RouteDictError::Invalid(route));
+            }
+            if dict.ids.contains_key(&route) {
+                return Err(RouteDictError::Duplicate(route));
+            }
+            if dict.routes.len() == u16::MAX as usize {
+                return Err(RouteDictError::TooMany);
+            }
+            dict.routes.push(route.clone());
+            dict.ids.insert(route, dict.routes.len() as u16);
+        }
+        dict.version = fnv1a(&dict.encode());
+        Ok(dict)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, RouteDictError> {
+        Self::from_routes(
+            text.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty()),
+        )
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, RouteDictError> {
+        let text = fs::read_to_string(path).map_err(RouteDictError::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn id(&self, route: &str) -> Option<u16> {
+        self.ids.get(route).copied()
+    }
+
+    pub fn route(&self, id: u16) -> Option<&str> {
+        if id == 0 {
+            return None;
+        }
+        self.routes.get(id as usize - 1).map(String::as_str)
+    }
+
+    /// routes in id order
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(String::as_str)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// routes separated by `\n` in id order, the format [`RouteDict::parse`] reads
+    pub fn encode(&self) -> Bytes {
+        Bytes::from(self.routes.join("\n"))
+    }
+}
+
+/// builds a [`RouteDict`] at startup, panics on duplicate or invalid routes
+///
+/// ```ignore
+/// let routes = orion::routes!["area.player.move", "chat.send"];
+/// ```
+#[macro_export]
+macro_rules! routes {
+    ($($route:expr),* $(,)?) => {
+        $crate::route::RouteDict::from_routes([$($route),*]).expect("invalid route dictionary")
+    };
+}
+
+// stable across builds and platforms, unlike `DefaultHasher`
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in bytes {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let dict = RouteDict::parse(
+            "# routes\n\
+             area.player.move\n\
+             \n\
+             chat.send # world chat\n",
+        )
+        .unwrap();
+        assert_eq!(dict.len(), 2);
+        assert_eq!(dict.id("area.player.move"), Some(1));
+        assert_eq!(dict.id("chat.send"), Some(2));
+        assert_eq!(dict.id("chat.recv"), None);
+        assert_eq!(dict.route(2), Some("chat.send"));
+        assert_eq!(dict.route(0), None);
+        assert_eq!(dict.route(3), None);
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let result = RouteDict::parse("chat.send\nchat.send");
+        assert!(matches!(result, Err(RouteDictError::Duplicate(r)) if r == "chat.send"));
+    }
+
+    #[test]
+    fn test_version() {
+        let a = crate::routes!["area.player.move", "chat.send"];
+        let b = RouteDict::parse("area.player.move\nchat.send").unwrap();
+        let c = crate::routes!["chat.send", "area.player.move"];
+        assert_eq!(a.version(), b.version());
+        assert_ne!(a.version(), c.version());
+    }
+
+    #[test]
+    fn test_encode() {
+        let dict = crate::routes!["area.player.move", "chat.send"];
+        let decoded = RouteDict::parse(std::str::from_utf8(&dict.encode()).unwrap()).unwrap();
+        assert_eq!(decoded.version(), dict.version());
+        assert_eq!(decoded.id("chat.send"), Some(2));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{fmt, future::Future};
+
+use async_nats::Message;
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+
+use crate::nats_client::{Concurrency, NatsClient, RequestError, SubscriptionError};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes::Bytes;
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Encode(serde_json::Error),
+    Decode(serde_json::Error),
+    Request(RequestError),
+    UnknownMethod(String),
+    /// the remote side failed to handle the call
+    Remote(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Encode(e) => write!(f, "failed to encode rpc payload: {}", e),
+            RpcError::Decode(e) => write!(f, "failed to decode rpc payload: {}", e),
+            RpcError::Request(e) => write!(f, "rpc request failed: {}", e),
+            RpcError::UnknownMethod(m) => write!(f, "unknown rpc method: {}", m),
+            RpcError::Remote(e) => write!(f, "remote rpc error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// subject format: `rpc.{service}.{method}`, or `rpc.{service}.{target}.{method}` when the
+/// call is meant for one server instance
+pub fn subject(service: &str, target: Option<&str>, method: &str) -> String {
+    match target {
+        Some(target) => format!("rpc.{}.{}.{}", service, target, method),
+        None => format!("rpc.{}.{}", service, method),
+    }
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Bytes, RpcError> {
+    serde_json::to_vec(value)
+        .map(Bytes::from)
+        .map_err(RpcError::Encode)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, RpcError> {
+    serde_json::from_slice(bytes).map_err(RpcError::Decode)
+}
+
+/// replies are encoded as `Result<T, String>` so the caller can tell a failed call from a bad reply
+pub fn encode_reply<T: Serialize>(value: &T) -> Result<Bytes, RpcError> {
+    encode(&Ok::<&T, String>(value))
+}
+
+pub fn decode_reply<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, RpcError> {
+    decode::<Result<T, String>>(bytes)?.map_err(RpcError::Remote)
+}
+
+/// calls of one method handled at the same time by one server
+const MAX_CONCURRENT_CALLS: usize = 64;
+
+/// subscribes [`subject`] and answers every request with the result of `handler`, calls
+/// without a target go to one server of the service only
+pub async fn serve_method<F, Fut>(
+    nats: &NatsClient,
+    service: &str,
+    target: Option<&str>,
+    method: &str,
+    handler: F,
+) -> Result<(), SubscriptionError>
+where
+    F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Bytes, RpcError>> + Send + 'static,
+{
+    let responder = nats.clone();
+    let handler = move |msg: Message| {
+        let call = msg.reply.map(|reply_to| (reply_to, handler(msg.payload)));
+        let subject = msg.subject;
+        let responder = responder.clone();
+        async move {
+            let Some((reply_to, fut)) = call else {
+                error!("Dropped rpc call without reply subject: {}", subject);
+                return;
+            };
+            let reply = match fut.await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    error!("Failed to handle rpc call: {}", e);
+                    match encode(&Err::<(), String>(e.to_string())) {
+                        Ok(reply) => reply,
+                        Err(_) => return,
+                    }
+                }
+            };
+            responder.publish(reply_to.to_string(), reply).await;
+        }
+    };
+    let subject = subject(service, target, method);
+    let concurrency = Concurrency::Bounded(MAX_CONCURRENT_CALLS);
+    match target {
+        Some(_) => nats.subscribe_async(subject, concurrency, handler).await?,
+        None => {
+            nats.queue_subscribe_async(subject, service.to_string(), concurrency, handler)
+                .await?
+        }
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::nats_client::MemoryBus;
+
+    #[crate::rpc]
+    trait Calculator {
+        async fn add(&self, a: i32, b: i32) -> i32;
+        async fn greet(&self, name: String) -> String;
+        async fn reset(&self);
+    }
+
+    struct MyCalculator;
+
+    impl Calculator for MyCalculator {
+        async fn add(&self, a: i32, b: i32) -> i32 {
+            a + b
+        }
+
+        async fn greet(&self, name: String) -> String {
+            format!("hello {}", name)
+        }
+
+        async fn reset(&self) {}
+    }
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(CalculatorServer::<MyCalculator>::SERVICE, "calculator");
+        assert_eq!(subject("calculator", None, "add"), "rpc.calculator.add");
+        assert_eq!(
+            subject("calculator", Some("7"), "add"),
+            "rpc.calculator.7.add"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let server = CalculatorServer::new(MyCalculator);
+
+        let reply = server
+            .dispatch("add", &encode(&(1, 2)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(decode_reply::<i32>(&reply).unwrap(), 3);
+
+        let reply = server
+            .dispatch("greet", &encode(&("orion",)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(decode_reply::<String>(&reply).unwrap(), "hello orion");
+
+        let reply = server.dispatch("reset", &encode(&()).unwrap()).await;
+        assert!(decode_reply::<()>(&reply.unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_errors() {
+        let server = CalculatorServer::new(MyCalculator);
+        let result = server.dispatch("sub", &encode(&(1, 2)).unwrap()).await;
+        assert!(matches!(result, Err(RpcError::UnknownMethod(_))));
+
+        let result = server.dispatch("add", b"not json").await;
+        assert!(matches!(result, Err(RpcError::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn test_serve_once() {
+        let nats = NatsClient::with_bus(MemoryBus::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let server = CalculatorServer::new(MyCalculator);
+            let calls = calls.clone();
+            serve_method(&nats, "calculator", None, "add", move |payload| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let server = server.clone();
+                async move { server.dispatch("add", &payload).await }
+            })
+            .await
+            .unwrap();
+        }
+        let client = CalculatorClient::new(nats);
+        assert_eq!(client.add(1, 2).await.unwrap(), 3);
+        tokio::task::yield_now().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_remote_error() {
+        let reply = encode(&Err::<(), String>("boom".to_string())).unwrap();
+        let result = decode_reply::<i32>(&reply);
+        assert!(matches!(result, Err(RpcError::Remote(e)) if e == "boom"));
+    }
+}
@@ -0,0 +1,354 @@
+use std::{collections::HashMap, fmt, future::Future, sync::Arc};
+
+use async_nats::{HeaderMap, Message};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tracing::{error, info, warn};
+
+use crate::{
+    app,
+    cluster::registry::{self, Announcement, ServerInfo},
+    codec::CodecError,
+    envelope::{self, Envelope},
+    nats_client::{Concurrency, NatsClient, Subscription, SubscriptionError},
+};
+
+#[derive(Debug)]
+pub enum HandlerError {
+    Codec(CodecError),
+    UnknownRoute(String),
+    Failed(String),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Codec(e) => write!(f, "{}", e),
+            HandlerError::UnknownRoute(route) => write!(f, "no handler for {}", route),
+            HandlerError::Failed(e) => write!(f, "handler failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<CodecError> for HandlerError {
+    fn from(e: CodecError) -> Self {
+        HandlerError::Codec(e)
+    }
+}
+
+type Handler =
+    Arc<dyn Fn(Envelope) -> BoxFuture<'static, Result<Bytes, HandlerError>> + Send + Sync>;
+
+/// The handlers of a backend server by route.
+#[derive(Clone, Default)]
+pub struct Handlers {
+    routes: HashMap<String, Handler>,
+}
+
+impl Handlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the handler's reply is the response body for requests and dropped for notifications
+    pub fn add<F, Fut>(&mut self, route: impl Into<String>, handler: F)
+    where
+        F: Fn(Envelope) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Bytes, HandlerError>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |envelope| Box::pin(handler(envelope)));
+        self.routes.insert(route.into(), handler);
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+
+    pub async fn dispatch(&self, envelope: Envelope) -> Result<Bytes, HandlerError> {
+        let Some(handler) = self.routes.get(&envelope.route) else {
+            return Err(HandlerError::UnknownRoute(envelope.route));
+        };
+        handler(envelope).await
+    }
+}
+
+/// A backend server: joins the cluster as the server type of the [`Application`](crate::app),
+/// takes the messages gates forward to its type and to itself and runs them through its
+/// handlers. The messages of one player are handled one at a time in the order they came,
+/// those of other players alongside them on up to [`lanes`](Self::lanes) workers.
+///
+/// ```ignore
+/// Server::new(nats)
+///     .handler("connector.entry.enter", enter)
+///     .run()
+///     .await;
+/// ```
+pub struct Server {
+    info: ServerInfo,
+    nats: NatsClient,
+    handlers: Handlers,
+    lanes: usize,
+}
+
+/// players whose messages are handled at the same time, per subject
+const DEFAULT_LANES: usize = 64;
+
+impl Server {
+    pub fn new(nats: NatsClient) -> Self {
+        Server {
+            info: ServerInfo::new(app().uuid(), app().server_type()),
+            nats,
+            handlers: Handlers::new(),
+            lanes: DEFAULT_LANES,
+        }
+    }
+
+    pub fn lanes(mut self, lanes: usize) -> Self {
+        self.lanes = lanes;
+        self
+    }
+
+    /// announced to the cluster with the server
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.info.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn handler<F, Fut>(mut self, route: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(Envelope) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Bytes, HandlerError>> + Send + 'static,
+    {
+        self.handlers.add(route, handler);
+        self
+    }
+
+    pub fn info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// serves until the application shuts down
+    pub async fn run(self) -> Result<(), SubscriptionError> {
+        let serving = self.serve().await?;
+        app().start().await;
+        serving.shutdown().await
+    }
+
+    /// subscribes and joins the cluster, then returns with the server taking messages in
+    /// the background; a server that fails to subscribe does not join
+    pub async fn serve(self) -> Result<Serving, SubscriptionError> {
+        let ServerInfo {
+            id, server_type, ..
+        } = &self.info;
+        let concurrency = Concurrency::ordered(self.lanes, player);
+        // messages for the type are shared out among its instances
+        let handlers = self.handlers.clone();
+        let nats = self.nats.clone();
+        let of_type = self
+            .nats
+            .queue_subscribe_async(
+                envelope::subject(server_type),
+                server_type.clone(),
+                concurrency.clone(),
+                move |msg| handle(handlers.clone(), nats.clone(), msg),
+            )
+            .await?;
+        let handlers = self.handlers.clone();
+        let nats = self.nats.clone();
+        let of_instance = self
+            .nats
+            .subscribe_async(
+                envelope::instance_subject(server_type, *id),
+                concurrency,
+                move |msg| handle(handlers.clone(), nats.clone(), msg),
+            )
+            .await?;
+        let announcement = registry::announce(&self.nats, self.info.clone()).await?;
+        info!(
+            "Server {} {} serving {} routes",
+            self.info.server_type,
+            self.info.id,
+            self.handlers.routes.len()
+        );
+        Ok(Serving {
+            announcement,
+            subscriptions: [of_type, of_instance],
+        })
+    }
+}
+
+/// A [`Server`] taking messages, from [`Server::serve`].
+pub struct Serving {
+    announcement: Announcement,
+    subscriptions: [Subscription; 2],
+}
+
+impl Serving {
+    pub fn info(&self) -> &ServerInfo {
+        self.announcement.info()
+    }
+
+    /// leaves the cluster and returns once the messages already taken are handled
+    pub async fn shutdown(self) -> Result<(), SubscriptionError> {
+        self.announcement.leave().await;
+        for subscription in self.subscriptions {
+            subscription.drain().await?;
+        }
+        Ok(())
+    }
+}
+
+/// the key of ordered handling, the uid the envelope was sent with
+fn player(msg: &Message) -> String {
+    envelope::uid(msg.headers.as_ref())
+        .unwrap_or_default()
+        .to_string()
+}
+
+async fn handle(handlers: Handlers, nats: NatsClient, msg: Message) {
+    let envelope = match Envelope::decode(msg.payload) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("Failed to decode envelope on {}: {}", msg.subject, e);
+            return;
+        }
+    };
+    let route = envelope.route.clone();
+    let result = handlers.dispatch(envelope).await;
+    // notifications have no one to answer
+    let Some(reply_to) = msg.reply else {
+        if let Err(e) = result {
+            warn!("Failed to handle {}: {}", route, e);
+        }
+        return;
+    };
+    match result {
+        Ok(reply) => nats.publish(reply_to.to_string(), reply).await,
+        Err(e) => {
+            warn!("Failed to handle {}: {}", route, e);
+            let mut headers = HeaderMap::new();
+            headers.insert(envelope::ERROR_HEADER, e.to_string().as_str());
+            nats.publish_with_headers(reply_to.to_string(), headers, Bytes::new())
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{cluster::registry::ServerRegistry, nats_client::MemoryBus};
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let mut handlers = Handlers::new();
+        handlers.add("connector.entry.enter", |envelope: Envelope| async move {
+            Ok(Bytes::from(format!("welcome {}", envelope.uid)))
+        });
+        handlers.add("connector.entry.leave", |_| async {
+            Err(HandlerError::Failed("not entered".to_string()))
+        });
+        let envelope = |route: &str| Envelope {
+            uid: "u1".to_string(),
+            route: route.to_string(),
+            ..Default::default()
+        };
+        let reply = handlers
+            .dispatch(envelope("connector.entry.enter"))
+            .await
+            .unwrap();
+        assert_eq!(reply, "welcome u1");
+        assert!(matches!(
+            handlers.dispatch(envelope("connector.entry.leave")).await,
+            Err(HandlerError::Failed(_))
+        ));
+        assert!(matches!(
+            handlers.dispatch(envelope("connector.entry.quit")).await,
+            Err(HandlerError::UnknownRoute(_))
+        ));
+    }
+
+    /// waits for the in-process bus to deliver to the registry
+    async fn eventually(check: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn test_in_process() {
+        let nats = NatsClient::with_bus(MemoryBus::new());
+        let registry = ServerRegistry::new();
+        registry.watch(&nats).await.unwrap();
+        let moves = Arc::new(Mutex::new(Vec::new()));
+        let seen = moves.clone();
+        let serving = Server::new(nats.clone())
+            .handler("area.entry.enter", |envelope: Envelope| async move {
+                Ok(Bytes::from(format!("welcome {}", envelope.uid)))
+            })
+            .handler("area.entry.move", move |envelope: Envelope| {
+                let moves = seen.clone();
+                async move {
+                    let step: u64 = String::from_utf8_lossy(&envelope.payload).parse().unwrap();
+                    // later moves finish sooner, unless they wait their turn
+                    tokio::time::sleep(std::time::Duration::from_millis(10 - step)).await;
+                    moves.lock().unwrap().push(step);
+                    Ok(Bytes::new())
+                }
+            })
+            .serve()
+            .await
+            .unwrap();
+        let ServerInfo {
+            id, server_type, ..
+        } = serving.info().clone();
+        eventually(|| registry.contains(&server_type, id)).await;
+
+        let envelope = |route: &str, payload: &str| Envelope {
+            uid: "u1".to_string(),
+            route: route.to_string(),
+            payload: Bytes::from(payload.to_string()),
+            ..Default::default()
+        };
+        let reply = nats
+            .try_request(
+                envelope::subject(&server_type),
+                envelope("area.entry.enter", "").encode(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.payload, "welcome u1");
+        let reply = nats
+            .try_request(
+                envelope::instance_subject(&server_type, id),
+                envelope("area.entry.quit", "").encode(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            envelope::reply_error(reply.headers.as_ref()),
+            Some("no handler for area.entry.quit")
+        );
+        for step in 0..5 {
+            let envelope = envelope("area.entry.move", &step.to_string());
+            nats.publish_with_headers(
+                envelope::subject(&server_type),
+                envelope.headers(),
+                envelope.encode(),
+            )
+            .await;
+        }
+
+        serving.shutdown().await.unwrap();
+        assert_eq!(*moves.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        eventually(|| !registry.contains(&server_type, id)).await;
+    }
+}
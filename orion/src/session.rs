@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// Per-player attributes kept by the gate, such as the chosen area server, character id or
+/// locale. A copy travels with every message forwarded to the backend servers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Session {
+    attrs: HashMap<String, Value>,
+}
+
+impl Session {
+    /// `None` if the key is missing or holds another type
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.attrs.get(key).and_then(|v| T::deserialize(v).ok())
+    }
+
+    pub fn set<T: Serialize>(
+        &mut self,
+        key: impl Into<String>,
+        value: T,
+    ) -> Result<(), serde_json::Error> {
+        self.attrs.insert(key.into(), serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.attrs.remove(key).is_some()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.attrs.contains_key(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attrs.is_empty()
+    }
+
+    pub fn apply(&mut self, update: SessionUpdate) {
+        for key in update.remove {
+            self.attrs.remove(&key);
+        }
+        self.attrs.extend(update.set);
+    }
+}
+
+/// changes sent by backend servers through [`SessionService::update`]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionUpdate {
+    #[serde(default)]
+    pub set: HashMap<String, Value>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl SessionUpdate {
+    pub fn set<T: Serialize>(
+        mut self,
+        key: impl Into<String>,
+        value: T,
+    ) -> Result<Self, serde_json::Error> {
+        self.set.insert(key.into(), serde_json::to_value(value)?);
+        Ok(self)
+    }
+
+    pub fn remove(mut self, key: impl Into<String>) -> Self {
+        self.remove.push(key.into());
+        self
+    }
+}
+
+/// Served by every gate at its server id, call it with `SessionServiceClient::new(nats).at(gate_id)`.
+#[crate::rpc(name = "session")]
+pub trait SessionService {
+    /// returns false if the player is not connected to this gate
+    async fn update(&self, uid: String, update: SessionUpdate) -> bool;
+    async fn get(&self, uid: String) -> Option<Session>;
+    /// moves the player to another server of `server_type`, for example on a map transfer.
+    /// Returns false if the player is not connected to this gate or the server is not live.
+    async fn bind(&self, uid: String, server_type: String, server_id: u32) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set() {
+        let mut session = Session::default();
+        session.set("character_id", 42u64).unwrap();
+        session.set("locale", "zh-CN").unwrap();
+        assert_eq!(session.get::<u64>("character_id"), Some(42));
+        assert_eq!(session.get::<String>("locale"), Some("zh-CN".to_string()));
+        assert_eq!(session.get::<u64>("locale"), None);
+        assert_eq!(session.get::<u64>("area"), None);
+        assert!(session.remove("locale"));
+        assert!(!session.contains("locale"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut session = Session::default();
+        session.set("locale", "en").unwrap();
+        session.set("area", 1).unwrap();
+        let update = SessionUpdate::default()
+            .set("area", 2)
+            .unwrap()
+            .remove("locale");
+        session.apply(update);
+        assert_eq!(session.get::<i32>("area"), Some(2));
+        assert!(!session.contains("locale"));
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut session = Session::default();
+        session.set("area", 2).unwrap();
+        let json = serde_json::to_string(&session).unwrap();
+        assert_eq!(json, r#"{"area":2}"#);
+        let decoded: Session = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, session);
+    }
+}